@@ -5,3 +5,8 @@ claim("program 33 at 0 0 0 1 0 1 1 0 1") -- play spotify
 claim("program 3 at 0 50 50 50 50 100 0 100") -- time is...
 claim("program 29 at 0 0 1 0 1 1 0 1") -- pointing at
 claim("program 10 at 0 0 1 0 1 1 0 1") -- outline programs
+claim("program 1 at 0 0 1 0 1 1 0 1") -- fox is red
+claim("program 2 at 0 0 1 0 1 1 0 1") -- is an animal
+claim("program 4 at 0 0 1 0 1 1 0 1") -- when someone is a fox
+claim("program 5 at 0 0 1 0 1 1 0 1") -- when time
+claim("program 6 at 0 0 1 0 1 1 0 1") -- you is a fox
@@ -5,7 +5,6 @@ when({"$ $animal is red"}, function (results)
     print("HELLO FROM LUA")
     print(results)
     print(#results)
-    retract("#2 %")
     for index, result in ipairs(results) do
         claim("red animal seen")
         print("I see a: "..result["animal"])
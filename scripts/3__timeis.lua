@@ -9,8 +9,6 @@ end
 
 when({"$ clock time is $t"}, function (results)
     -- print("clock "..sub_id)
-    retract("#3 time is %")
-    retract("#3 wish you had graphics %")
     for index, result in ipairs(results) do
         claim("time is "..os.time())
         draw("time is "..os.time())
@@ -19,7 +17,6 @@ end)
 
 when({"$ fps is $fps"}, function (results)
     -- print("fps "..sub_id)
-    retract("#3 wish window had graphics %")
     for index, result in ipairs(results) do
         table.insert(cache, result["fps"])
         if #cache > 10 then
@@ -8,7 +8,6 @@
 --     end
 -- end)
 when({"$ ble $ says button is pressed"}, function (results)
-    retract("#5 %")
     for index, result in ipairs(results) do
         local ill = Illumination.new()
         ill:text{x=0, y=40, text="BUTTON PRESSED", size=100}
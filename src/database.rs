@@ -1,23 +1,59 @@
-use crate::fact::{Fact, Term};
+use crate::fact::{Fact, FactParseError, Term};
+use crate::history::{History, HistoryEvent, HistoryEventKind, HistoryUnavailable};
+use crate::index::{Arity, FactId, FactIndex, TermKey};
+use crate::persistence::{Persistence, PersistenceConfig};
+use crate::query::{Clause, PlanStep, Query, QueryError, QueryPlan};
 use mlua::RegistryKey;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::time::{Duration, Instant, SystemTime};
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct QueryResultVariable {
     pub variable_name: String,
     pub term: Term,
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct QueryResult {
     pub result: Vec<QueryResultVariable>,
 }
 
+/// How a subscription's results changed since its callback last ran.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResultChanges {
+    pub results: Vec<QueryResult>,
+    pub added: Vec<QueryResult>,
+    pub removed: Vec<QueryResult>,
+}
+
+/// The results in `a` that are not in `b`, counting duplicates.
+fn results_difference(a: &[QueryResult], b: &[QueryResult]) -> Vec<QueryResult> {
+    let mut unmatched: Vec<&QueryResult> = b.iter().collect();
+    let mut difference: Vec<QueryResult> = vec![];
+    for result in a.iter() {
+        match unmatched.iter().position(|r| *r == result) {
+            Some(i) => {
+                unmatched.swap_remove(i);
+            }
+            None => difference.push(result.clone()),
+        }
+    }
+    difference
+}
+
+pub type SubscriptionId = u64;
+
 #[derive(Debug)]
 pub struct Subscription {
+    /// Assigned by `Database::add_subscription`.
+    pub id: SubscriptionId,
     pub program_source_id: String,
     pub query_parts: Vec<String>,
     pub callback_func: RegistryKey,
     pub last_results: Vec<QueryResult>,
+    /// Whether the callback has run yet, so that it also runs once for empty results.
+    pub has_run: bool,
 }
 impl Subscription {
     pub fn new(
@@ -26,42 +62,583 @@ impl Subscription {
         callback_func: RegistryKey,
     ) -> Self {
         Subscription {
+            id: 0,
             program_source_id: program_source_id.to_owned(),
             query_parts: query_parts.to_owned(),
             callback_func,
             last_results: vec![],
+            has_run: false,
+        }
+    }
+
+    /// Stores the latest results, returning what changed or `None` if the callback has
+    /// already seen the same results (in any order).
+    pub fn update_results(&mut self, results: Vec<QueryResult>) -> Option<ResultChanges> {
+        let added = results_difference(&results, &self.last_results);
+        let removed = results_difference(&self.last_results, &results);
+        if self.has_run && added.is_empty() && removed.is_empty() {
+            return None;
+        }
+        self.has_run = true;
+        self.last_results = results.clone();
+        Some(ResultChanges {
+            results,
+            added,
+            removed,
+        })
+    }
+}
+
+/// How long a fact claimed with `Database::claim_with_expiry` lives.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Expiry {
+    /// Wall-clock time, checked whenever the tick advances.
+    After(Duration),
+    /// Gone once the tick has advanced this many times.
+    Ticks(u64),
+}
+
+/// Where a fact came from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FactSource {
+    /// Claimed by a program or the app itself.
+    Local,
+    /// Claimed by the camera thread.
+    Vision,
+    Network,
+    /// Loaded back from disk by `Database::with_persistence`.
+    Restored,
+}
+impl FactSource {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FactSource::Local => "local",
+            FactSource::Vision => "vision",
+            FactSource::Network => "network",
+            FactSource::Restored => "restored",
         }
     }
 }
 
+/// What the database knows about how a fact got there.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FactMetadata {
+    /// See `Fact::owner`.
+    pub owner: Option<String>,
+    /// The subscription whose callback claimed the fact, if any.
+    pub subscription_id: Option<SubscriptionId>,
+    pub created_tick: u64,
+    pub created_at: SystemTime,
+    pub source: FactSource,
+}
+
+/// A claim or retract made while a transaction is open, applied when it commits.
+enum Change {
+    Claim(Fact, FactSource),
+    ClaimForSubscription(SubscriptionId, Fact),
+    ClaimWithExpiry(Fact, Expiry),
+    /// A pattern and, for scoped retracts, the only owner whose facts it may remove.
+    Retract(Fact, Option<String>),
+    RetractSubscriptionFacts(SubscriptionId),
+}
+
+enum Deadline {
+    Time(Instant),
+    Tick(u64),
+}
+
 pub struct Database {
-    facts: Vec<Fact>,
+    facts: BTreeMap<FactId, Fact>,
+    next_fact_id: FactId,
+    index: FactIndex,
     pub subscriptions: Vec<Subscription>,
+    next_subscription_id: SubscriptionId,
+    /// Facts claimed from inside each subscription's callback. Ids of facts that were
+    /// retracted some other way are left in place; fact ids are never reused.
+    subscription_facts: HashMap<SubscriptionId, Vec<FactId>>,
+    persistence: Option<Persistence>,
+    /// Facts that are in the log or snapshot and so need their retraction logged too.
+    persisted_facts: HashSet<FactId>,
+    /// Restored facts that haven't been claimed again since the restart. A program claiming
+    /// one of them again on boot takes it over instead of storing another copy.
+    restored_facts: HashMap<Vec<TermKey>, Vec<FactId>>,
+    history: History,
+    expiring_facts: HashMap<FactId, Deadline>,
+    metadata: HashMap<FactId, FactMetadata>,
+    /// Changes waiting for `commit` while a transaction is open.
+    transaction: Option<Vec<Change>>,
+    deduplicate: bool,
+    /// With `deduplicate` on, the ids of all claims of each fact in claim order. Only the
+    /// first is in the index, so queries see the fact once however many times it is claimed.
+    duplicates: HashMap<Vec<TermKey>, Vec<FactId>>,
 }
 impl Database {
     pub fn new() -> Self {
         Database {
-            facts: vec![],
+            facts: BTreeMap::new(),
+            next_fact_id: 0,
+            index: FactIndex::new(),
             subscriptions: vec![],
+            next_subscription_id: 0,
+            subscription_facts: HashMap::new(),
+            persistence: None,
+            persisted_facts: HashSet::new(),
+            restored_facts: HashMap::new(),
+            history: History::new(10_000),
+            expiring_facts: HashMap::new(),
+            metadata: HashMap::new(),
+            transaction: None,
+            deduplicate: false,
+            duplicates: HashMap::new(),
+        }
+    }
+
+    /// A database that restores the facts saved in `config.directory` and saves claims and
+    /// retracts there from now on. Facts claimed by subscription callbacks are not saved
+    /// since the callbacks claim them again when they run.
+    pub fn with_persistence(config: PersistenceConfig) -> io::Result<Self> {
+        let (persistence, facts) = Persistence::open(config)?;
+        let mut db = Database::from_facts(facts);
+        db.persisted_facts = db.facts.keys().cloned().collect();
+        for metadata in db.metadata.values_mut() {
+            metadata.source = FactSource::Restored;
+        }
+        for (id, fact) in db.facts.iter() {
+            if let Some(key) = Self::duplicate_key(fact) {
+                db.restored_facts
+                    .entry(key)
+                    .or_insert_with(Vec::new)
+                    .push(*id);
+            }
+        }
+        db.persistence = Some(persistence);
+        Ok(db)
+    }
+
+    fn from_facts(facts: BTreeMap<FactId, Fact>) -> Self {
+        let mut db = Database::new();
+        for (id, fact) in facts {
+            db.index_fact(id, &fact);
+            db.metadata.insert(id, db.new_metadata(&fact));
+            db.facts.insert(id, fact);
+            db.next_fact_id = id + 1;
+        }
+        db
+    }
+
+    fn new_metadata(&self, fact: &Fact) -> FactMetadata {
+        FactMetadata {
+            owner: fact.owner().map(str::to_owned),
+            subscription_id: None,
+            created_tick: self.tick(),
+            created_at: SystemTime::now(),
+            source: FactSource::Local,
         }
     }
 
     pub fn print(&self) {
         println!("DATABASE:");
         self.facts
-            .iter()
+            .values()
             .for_each(|f| println!("{}", f.to_string()));
     }
 
     pub fn claim(&mut self, fact: Fact) {
-        self.facts.push(fact);
+        self.change(Change::Claim(fact, FactSource::Local));
     }
 
-    pub fn retract(&mut self, fact_query_str: &str) {
-        let fact_query = Fact::from_string(fact_query_str);
-        let mut empty_query_result = QueryResult { result: vec![] };
-        self.facts
-            .retain(|fact| !Self::fact_match(&fact_query, fact, &mut empty_query_result))
+    /// Like `claim`, for facts that did not come from a program, e.g. from the camera.
+    pub fn claim_from(&mut self, source: FactSource, fact: Fact) {
+        self.change(Change::Claim(fact, source));
+    }
+
+    /// Claims a fact on behalf of a subscription's callback. The fact is retracted by
+    /// `retract_subscription_facts` when the callback runs again or the subscription goes away.
+    pub fn claim_for_subscription(&mut self, subscription_id: SubscriptionId, fact: Fact) {
+        self.change(Change::ClaimForSubscription(subscription_id, fact));
+    }
+
+    /// Claims a fact that the database retracts by itself once `expiry` has passed. These
+    /// facts are never persisted and are not owned by a running subscription.
+    pub fn claim_with_expiry(&mut self, fact: Fact, expiry: Expiry) {
+        self.change(Change::ClaimWithExpiry(fact, expiry));
+    }
+
+    fn expire_facts(&mut self) {
+        let now = Instant::now();
+        let tick = self.tick();
+        let expired: Vec<FactId> = self
+            .expiring_facts
+            .iter()
+            .filter(|(_, deadline)| match deadline {
+                Deadline::Time(time) => *time <= now,
+                Deadline::Tick(deadline_tick) => *deadline_tick <= tick,
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            self.remove_fact(id);
+        }
+    }
+
+    pub fn retract_subscription_facts(&mut self, subscription_id: SubscriptionId) {
+        self.change(Change::RetractSubscriptionFacts(subscription_id));
+    }
+
+    /// Starts buffering claims and retracts so they all take effect at once on `commit`.
+    /// Queries keep seeing the facts as they were before the transaction began.
+    pub fn begin(&mut self) {
+        if self.transaction.is_some() {
+            panic!("a transaction is already open");
+        }
+        self.transaction = Some(vec![]);
+    }
+
+    /// Applies the changes made since `begin`, in the order they were made.
+    pub fn commit(&mut self) {
+        if let Some(changes) = self.transaction.take() {
+            for change in changes {
+                self.apply(change);
+            }
+        }
+    }
+
+    /// Drops the changes made since `begin`.
+    pub fn rollback(&mut self) {
+        self.transaction = None;
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.transaction.is_some()
+    }
+
+    fn change(&mut self, change: Change) {
+        match self.transaction.as_mut() {
+            Some(changes) => changes.push(change),
+            None => self.apply(change),
+        }
+    }
+
+    fn apply(&mut self, change: Change) {
+        match change {
+            Change::Claim(fact, source) => match self.take_restored_fact(&fact) {
+                Some(id) => {
+                    let mut metadata = self.new_metadata(&fact);
+                    metadata.source = source;
+                    self.metadata.insert(id, metadata);
+                }
+                None => {
+                    let id = self.insert_fact(fact);
+                    if let Some(metadata) = self.metadata.get_mut(&id) {
+                        metadata.source = source;
+                    }
+                    self.persist_claim(id);
+                }
+            },
+            Change::ClaimForSubscription(subscription_id, fact) => {
+                let id = self.insert_fact(fact);
+                if let Some(metadata) = self.metadata.get_mut(&id) {
+                    metadata.subscription_id = Some(subscription_id);
+                }
+                self.subscription_facts
+                    .entry(subscription_id)
+                    .or_insert_with(Vec::new)
+                    .push(id);
+            }
+            Change::ClaimWithExpiry(fact, expiry) => {
+                let id = self.insert_fact(fact);
+                let deadline = match expiry {
+                    Expiry::After(duration) => Deadline::Time(Instant::now() + duration),
+                    Expiry::Ticks(ticks) => Deadline::Tick(self.tick() + ticks),
+                };
+                self.expiring_facts.insert(id, deadline);
+            }
+            Change::Retract(fact_query, owner) => {
+                for id in self.retractable_fact_ids(&fact_query, owner.as_deref()) {
+                    self.remove_fact(id);
+                }
+            }
+            Change::RetractSubscriptionFacts(subscription_id) => {
+                if let Some(ids) = self.subscription_facts.remove(&subscription_id) {
+                    for id in ids {
+                        self.remove_fact(id);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The id of a restored copy of `fact` that nobody has claimed again yet, if there is
+    /// one, which from now on stands for this claim.
+    fn take_restored_fact(&mut self, fact: &Fact) -> Option<FactId> {
+        let key = Self::duplicate_key(fact)?;
+        let ids = self.restored_facts.get_mut(&key)?;
+        let facts = &self.facts;
+        ids.retain(|id| facts.contains_key(id));
+        let id = if ids.is_empty() {
+            None
+        } else {
+            Some(ids.remove(0))
+        };
+        if ids.is_empty() {
+            self.restored_facts.remove(&key);
+        }
+        id
+    }
+
+    fn insert_fact(&mut self, fact: Fact) -> FactId {
+        let id = self.next_fact_id;
+        self.next_fact_id += 1;
+        self.index_fact(id, &fact);
+        self.history.record(HistoryEventKind::Claim, id, &fact);
+        self.metadata.insert(id, self.new_metadata(&fact));
+        self.facts.insert(id, fact);
+        id
+    }
+
+    /// Turns set semantics on or off. With it on, identical facts are stored once per claim
+    /// but match queries once, and the fact stays until every claim of it is retracted.
+    pub fn set_deduplicate(&mut self, deduplicate: bool) {
+        self.deduplicate = deduplicate;
+        self.index = FactIndex::new();
+        self.duplicates.clear();
+        let facts: Vec<(FactId, Fact)> = self
+            .facts
+            .iter()
+            .map(|(id, fact)| (*id, fact.clone()))
+            .collect();
+        for (id, fact) in facts {
+            self.index_fact(id, &fact);
+        }
+    }
+
+    /// Facts with variables in them are never treated as duplicates.
+    fn duplicate_key(fact: &Fact) -> Option<Vec<TermKey>> {
+        fact.terms.iter().map(TermKey::new).collect()
+    }
+
+    fn index_fact(&mut self, id: FactId, fact: &Fact) {
+        if self.deduplicate {
+            if let Some(key) = Self::duplicate_key(fact) {
+                let ids = self.duplicates.entry(key).or_insert_with(Vec::new);
+                ids.push(id);
+                if ids.len() > 1 {
+                    return;
+                }
+            }
+        }
+        self.index.insert(id, fact);
+    }
+
+    fn unindex_fact(&mut self, id: FactId, fact: &Fact) {
+        if self.deduplicate {
+            if let Some(key) = Self::duplicate_key(fact) {
+                if let Some(ids) = self.duplicates.get_mut(&key) {
+                    let was_indexed = ids.first() == Some(&id);
+                    ids.retain(|other| *other != id);
+                    let next = ids.first().cloned();
+                    if ids.is_empty() {
+                        self.duplicates.remove(&key);
+                    }
+                    if !was_indexed {
+                        return;
+                    }
+                    // The next claim of the same fact takes over its place in the index
+                    if let Some(next) = next {
+                        self.index.insert(next, &self.facts[&next]);
+                    }
+                }
+            }
+        }
+        self.index.remove(id, fact);
+    }
+
+    /// `ids` plus the ids of every other claim of the same facts.
+    fn with_duplicates(&self, ids: Vec<FactId>) -> Vec<FactId> {
+        if !self.deduplicate {
+            return ids;
+        }
+        let mut all_ids: Vec<FactId> = vec![];
+        for id in ids {
+            match Self::duplicate_key(&self.facts[&id]).and_then(|key| self.duplicates.get(&key)) {
+                Some(duplicates) => all_ids.extend(duplicates.iter().cloned()),
+                None => all_ids.push(id),
+            }
+        }
+        all_ids.sort();
+        all_ids
+    }
+
+    fn remove_fact(&mut self, id: FactId) {
+        if let Some(fact) = self.facts.remove(&id) {
+            self.unindex_fact(id, &fact);
+            self.history.record(HistoryEventKind::Retract, id, &fact);
+        }
+        self.expiring_facts.remove(&id);
+        self.metadata.remove(&id);
+        if self.persisted_facts.remove(&id) {
+            if let Some(persistence) = self.persistence.as_mut() {
+                if let Err(e) = persistence.log_retract(id) {
+                    println!("failed to log retract of fact {}: {}", id, e);
+                }
+            }
+            self.snapshot_if_needed();
+        }
+    }
+
+    fn persist_claim(&mut self, id: FactId) {
+        let persistence = match self.persistence.as_mut() {
+            Some(persistence) => persistence,
+            None => return,
+        };
+        if !persistence.config().persists(&self.facts[&id]) {
+            return;
+        }
+        match persistence.log_claim(id, &self.facts[&id]) {
+            Ok(()) => {
+                self.persisted_facts.insert(id);
+            }
+            Err(e) => println!("failed to log claim of fact {}: {}", id, e),
+        }
+        self.snapshot_if_needed();
+    }
+
+    fn snapshot_if_needed(&mut self) {
+        if let Some(persistence) = self.persistence.as_mut() {
+            if persistence.needs_snapshot() {
+                let persisted_facts = &self.persisted_facts;
+                let facts = self
+                    .facts
+                    .iter()
+                    .filter(|(id, _)| persisted_facts.contains(id));
+                if let Err(e) = persistence.write_snapshot(facts) {
+                    println!("failed to write database snapshot: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Retracts every fact matching the pattern, whoever claimed it, and returns how many
+    /// there were. Inside a transaction the count includes what the transaction claimed and
+    /// retracted so far; the facts are removed on commit.
+    pub fn retract(&mut self, fact_query_str: &str) -> Result<usize, FactParseError> {
+        let fact_query = Fact::parse(fact_query_str)?;
+        Ok(self.retract_scoped(fact_query, None))
+    }
+
+    /// Like `retract`, but only removes facts owned by `owner`, i.e. starting with `#owner`.
+    pub fn retract_owned(
+        &mut self,
+        owner: &str,
+        fact_query_str: &str,
+    ) -> Result<usize, FactParseError> {
+        let fact_query = Fact::parse(fact_query_str)?;
+        Ok(self.retract_scoped(fact_query, Some(owner.to_string())))
+    }
+
+    fn retract_scoped(&mut self, fact_query: Fact, owner: Option<String>) -> usize {
+        let count = self.retract_count(&fact_query, owner.as_deref());
+        self.change(Change::Retract(fact_query, owner));
+        count
+    }
+
+    /// How many facts a retract would remove at this point of the open transaction, if any:
+    /// the committed facts it hasn't retracted yet plus the facts it has claimed since.
+    fn retract_count(&self, fact_query: &Fact, owner: Option<&str>) -> usize {
+        let changes = match self.transaction.as_ref() {
+            Some(changes) => &changes[..],
+            None => &[],
+        };
+        let mut retracted: HashSet<FactId> = HashSet::new();
+        let mut claimed: Vec<(Option<SubscriptionId>, &Fact)> = vec![];
+        for change in changes {
+            match change {
+                Change::Claim(fact, _) | Change::ClaimWithExpiry(fact, _) => {
+                    claimed.push((None, fact))
+                }
+                Change::ClaimForSubscription(subscription_id, fact) => {
+                    claimed.push((Some(*subscription_id), fact))
+                }
+                Change::Retract(query, query_owner) => {
+                    retracted.extend(self.retractable_fact_ids(query, query_owner.as_deref()));
+                    claimed
+                        .retain(|(_, fact)| !Self::retracts(query, query_owner.as_deref(), fact));
+                }
+                Change::RetractSubscriptionFacts(subscription_id) => {
+                    if let Some(ids) = self.subscription_facts.get(subscription_id) {
+                        retracted.extend(ids.iter().cloned());
+                    }
+                    claimed.retain(|(claimed_for, _)| *claimed_for != Some(*subscription_id));
+                }
+            }
+        }
+        let committed = self
+            .retractable_fact_ids(fact_query, owner)
+            .into_iter()
+            .filter(|id| !retracted.contains(id))
+            .count();
+        committed
+            + claimed
+                .iter()
+                .filter(|(_, fact)| Self::retracts(fact_query, owner, fact))
+                .count()
+    }
+
+    fn retracts(fact_query: &Fact, owner: Option<&str>, fact: &Fact) -> bool {
+        (owner.is_none() || fact.owner() == owner)
+            && Self::fact_match(fact_query, fact, &mut QueryResult { result: vec![] })
+    }
+
+    /// A retract takes back every claim of the matching facts.
+    fn retractable_fact_ids(&self, fact_query: &Fact, owner: Option<&str>) -> Vec<FactId> {
+        let empty_query_result = QueryResult { result: vec![] };
+        let ids = self.matching_fact_ids(fact_query, &empty_query_result);
+        self.with_duplicates(ids)
+            .into_iter()
+            .filter(|id| match owner {
+                Some(owner) => self.metadata[id].owner.as_deref() == Some(owner),
+                None => true,
+            })
+            .collect()
+    }
+
+    /// The facts matching `pattern` along with who claimed them and when, in claim order.
+    pub fn provenance(&self, pattern: &str) -> Result<Vec<(Fact, FactMetadata)>, FactParseError> {
+        let pattern = Fact::parse(pattern)?;
+        let empty_query_result = QueryResult { result: vec![] };
+        let ids = self.matching_fact_ids(&pattern, &empty_query_result);
+        Ok(self
+            .with_duplicates(ids)
+            .into_iter()
+            .map(|id| (self.facts[&id].clone(), self.metadata[&id].clone()))
+            .collect())
+    }
+
+    /// Uses the indexes to find the ids of facts that match `query` under `env`.
+    fn matching_fact_ids(&self, query: &Fact, env: &QueryResult) -> Vec<FactId> {
+        let mut constants: Vec<(usize, TermKey)> = vec![];
+        for (position, term) in query.terms.iter().enumerate() {
+            let bound_term = match term {
+                Term::Postfix(_) => break,
+                Term::Variable(variable_name) => env
+                    .result
+                    .iter()
+                    .find(|r| r.variable_name.eq(variable_name))
+                    .map(|r| &r.term),
+                _ => Some(term),
+            };
+            if let Some(key) = bound_term.and_then(TermKey::new) {
+                constants.push((position, key));
+            }
+        }
+        self.index
+            .candidates(Arity::of_pattern(query), &constants)
+            .into_iter()
+            .filter(|id| {
+                let mut new_env = env.clone();
+                Self::fact_match(query, &self.facts[id], &mut new_env)
+            })
+            .collect()
     }
 
     fn term_match(a: &Term, b: &Term, env: &mut QueryResult) -> bool {
@@ -102,47 +679,285 @@ impl Database {
             return false;
         }
         for (i, a_term) in fact_a.terms.iter().enumerate() {
-            if !Self::term_match(a_term, &fact_b.terms[i], env) {
-                return false;
-            }
             if let Term::Postfix(variable_name) = a_term {
+                // The postfix binds everything that is left, not just the term at `i`
                 if !variable_name.is_empty() {
-                    env.result.push(QueryResultVariable {
-                        variable_name: variable_name.to_string(),
-                        term: Term::Text(Fact::from_terms(&fact_b.terms[i..]).to_string()),
-                    })
+                    let rest = Term::Text(Fact::from_terms(&fact_b.terms[i..]).to_string());
+                    let rest_variable = Term::Variable(variable_name.to_owned());
+                    if !Self::term_match(&rest_variable, &rest, env) {
+                        return false;
+                    }
                 }
                 break;
             }
+            if !Self::term_match(a_term, &fact_b.terms[i], env) {
+                return false;
+            }
         }
         return true;
     }
 
-    fn collect_solutions(&self, query: &[Fact], env: &QueryResult) -> Vec<QueryResult> {
+    fn collect_solutions(&self, query: &[&Clause], env: &QueryResult) -> Vec<QueryResult> {
         if query.is_empty() {
-            vec![env.clone()]
-        } else {
-            let mut solutions: Vec<QueryResult> = vec![];
-            for f in &self.facts {
-                let mut new_env: QueryResult = env.clone();
-                if Self::fact_match(&query[0], f, &mut new_env) {
+            return vec![env.clone()];
+        }
+        match query[0] {
+            Clause::Pattern(pattern) => {
+                let mut solutions: Vec<QueryResult> = vec![];
+                for id in self.matching_fact_ids(pattern, env) {
+                    let mut new_env: QueryResult = env.clone();
+                    Self::fact_match(pattern, &self.facts[&id], &mut new_env);
                     for solution in self.collect_solutions(&query[1..], &new_env) {
                         solutions.push(solution);
                     }
                 }
+                solutions
+            }
+            Clause::Not(pattern) => {
+                if self.matching_fact_ids(pattern, env).is_empty() {
+                    self.collect_solutions(&query[1..], env)
+                } else {
+                    vec![]
+                }
+            }
+            predicate => {
+                if predicate.passes(env) {
+                    self.collect_solutions(&query[1..], env)
+                } else {
+                    vec![]
+                }
             }
-            return solutions;
         }
     }
 
+    fn estimate_clause(&self, clause: &Clause, bound_variables: &[String]) -> usize {
+        let clause = match clause {
+            Clause::Pattern(pattern) | Clause::Not(pattern) => pattern,
+            // Predicates only look at the current bindings, not at any facts
+            _ => return 0,
+        };
+        let mut constants: Vec<(usize, TermKey)> = vec![];
+        let mut bound_positions: Vec<usize> = vec![];
+        for (position, term) in clause.terms.iter().enumerate() {
+            match term {
+                Term::Postfix(_) => break,
+                Term::Variable(name) if bound_variables.contains(name) => {
+                    bound_positions.push(position)
+                }
+                _ => {
+                    if let Some(key) = TermKey::new(term) {
+                        constants.push((position, key));
+                    }
+                }
+            }
+        }
+        self.index
+            .estimate(Arity::of_pattern(clause), &constants, &bound_positions)
+    }
+
+    /// Orders the clauses greedily so each step is the one expected to match the fewest
+    /// facts given the variables bound by the steps before it. Filters like `not` clauses
+    /// go as early as possible, once the variables they share with patterns are bound.
+    pub fn plan(&self, query: &Query) -> QueryPlan {
+        let mut bound_variables: Vec<String> = vec![];
+        let mut remaining: Vec<usize> = (0..query.clauses.len()).collect();
+        let mut steps: Vec<PlanStep> = vec![];
+        while !remaining.is_empty() {
+            let ready_filter = remaining.iter().cloned().find(|i| {
+                query.clauses[*i].is_filter()
+                    && query
+                        .filter_inputs(*i)
+                        .iter()
+                        .all(|name| bound_variables.contains(name))
+            });
+            let cheapest_pattern = remaining
+                .iter()
+                .filter(|i| matches!(query.clauses[**i], Clause::Pattern(_)))
+                .map(|i| {
+                    (
+                        *i,
+                        self.estimate_clause(&query.clauses[*i], &bound_variables),
+                    )
+                })
+                .min_by_key(|(_, estimate)| *estimate);
+            let (clause_index, estimated_rows) = match (ready_filter, cheapest_pattern) {
+                (Some(i), _) => (i, self.estimate_clause(&query.clauses[i], &bound_variables)),
+                (None, Some(pattern)) => pattern,
+                // Only aggregates are left, which run once everything else has
+                (None, None) => (remaining[0], 0),
+            };
+            remaining.retain(|i| *i != clause_index);
+            let clause = &query.clauses[clause_index];
+            let variables = clause.variables();
+            steps.push(PlanStep {
+                clause_index,
+                clause: clause.to_string(),
+                bound_variables: variables
+                    .iter()
+                    .filter(|name| bound_variables.contains(name))
+                    .cloned()
+                    .collect(),
+                estimated_rows,
+            });
+            if let Clause::Pattern(_) = clause {
+                for name in variables {
+                    if !bound_variables.contains(&name) {
+                        bound_variables.push(name);
+                    }
+                }
+            }
+        }
+        QueryPlan { steps }
+    }
+
+    /// Evaluates `query` in planned order. Each result lists its variables in the order they
+    /// appear in the query; the order of the results themselves follows the plan.
+    pub fn query(&self, query: &Query) -> Vec<QueryResult> {
+        let plan = self.plan(query);
+        let clauses: Vec<&Clause> = plan
+            .steps
+            .iter()
+            .map(|step| &query.clauses[step.clause_index])
+            .filter(|clause| !matches!(clause, Clause::Aggregate { .. }))
+            .collect();
+        let variable_names = query.variable_names();
+        let mut solutions = self.collect_solutions(&clauses, &QueryResult { result: vec![] });
+        for solution in solutions.iter_mut() {
+            solution.result.sort_by_key(|r| {
+                variable_names
+                    .iter()
+                    .position(|name| name.eq(&r.variable_name))
+            });
+        }
+        if query.has_aggregates() {
+            Self::aggregate(query, solutions)
+        } else {
+            solutions
+        }
+    }
+
+    /// Groups `solutions` by the query's group variables, in order of first appearance, and
+    /// replaces each group with one result holding the group variables and the aggregates.
+    fn aggregate(query: &Query, solutions: Vec<QueryResult>) -> Vec<QueryResult> {
+        let group_names = query.group_variable_names();
+        let mut groups: Vec<(Vec<QueryResultVariable>, Vec<QueryResult>)> = vec![];
+        if group_names.is_empty() {
+            // A count over nothing is still a count
+            groups.push((vec![], vec![]));
+        }
+        for solution in solutions {
+            let key: Vec<QueryResultVariable> = solution
+                .result
+                .iter()
+                .filter(|r| group_names.contains(&r.variable_name))
+                .cloned()
+                .collect();
+            match groups.iter_mut().find(|(group_key, _)| *group_key == key) {
+                Some((_, members)) => members.push(solution),
+                None => groups.push((key, vec![solution])),
+            }
+        }
+        groups
+            .into_iter()
+            .filter_map(|(mut result, members)| {
+                for clause in query.clauses.iter() {
+                    if let Clause::Aggregate {
+                        output,
+                        function,
+                        input,
+                    } = clause
+                    {
+                        let values: Vec<Term> = members
+                            .iter()
+                            .filter_map(|m| {
+                                m.result
+                                    .iter()
+                                    .find(|r| r.variable_name.eq(input))
+                                    .map(|r| r.term.clone())
+                            })
+                            .collect();
+                        result.push(QueryResultVariable {
+                            variable_name: output.to_owned(),
+                            term: function.apply(&values)?,
+                        });
+                    }
+                }
+                Some(QueryResult { result })
+            })
+            .collect()
+    }
+
     pub fn select(&self, query_parts: &Vec<String>) -> Vec<QueryResult> {
-        let query: Vec<Fact> = query_parts.iter().map(|p| Fact::from_string(p)).collect();
-        return self.collect_solutions(&query[..], &QueryResult { result: vec![] });
+        match Query::parse(query_parts) {
+            Ok(query) => self.query(&query),
+            Err(e) => {
+                println!("invalid query {:?}: {}", query_parts, e);
+                vec![]
+            }
+        }
+    }
+
+    /// The current tick of the history. Claims and retracts are recorded under it.
+    pub fn tick(&self) -> u64 {
+        self.history.tick()
+    }
+
+    /// Moves on to the next tick and retracts the facts that expired by then.
+    pub fn advance_tick(&mut self) -> u64 {
+        let tick = self.history.advance_tick();
+        self.expire_facts();
+        tick
+    }
+
+    /// How many claims and retracts to remember for `select_as_of` and `changes_between`.
+    pub fn set_history_capacity(&mut self, capacity: usize) {
+        self.history.set_capacity(capacity);
+    }
+
+    /// Runs `select` against the facts as they were at the end of `tick`.
+    pub fn select_as_of(
+        &self,
+        tick: u64,
+        query_parts: &Vec<String>,
+    ) -> Result<Vec<QueryResult>, HistoryUnavailable> {
+        let mut facts = self.facts.clone();
+        self.history.rewind(&mut facts, tick)?;
+        let mut db = Database::from_facts(facts);
+        db.set_deduplicate(self.deduplicate);
+        Ok(db.select(query_parts))
+    }
+
+    /// The claims and retracts made after tick `from` up to and including tick `to`.
+    pub fn changes_between(&self, from: u64, to: u64) -> Vec<&HistoryEvent> {
+        self.history.changes_between(from, to)
+    }
+
+    /// The plan `select` would use for `query_parts`, for debugging slow queries.
+    pub fn explain(&self, query_parts: &Vec<String>) -> Result<QueryPlan, QueryError> {
+        Ok(self.plan(&Query::parse(query_parts)?))
     }
 
+    pub fn add_subscription(&mut self, mut subscription: Subscription) -> SubscriptionId {
+        subscription.id = self.next_subscription_id;
+        self.next_subscription_id += 1;
+        let id = subscription.id;
+        self.subscriptions.push(subscription);
+        id
+    }
+
+    /// Removes the program's subscriptions along with the facts their callbacks claimed.
     pub fn remove_subscriptions_by_program(&mut self, program_source_id: &String) {
+        let removed: Vec<SubscriptionId> = self
+            .subscriptions
+            .iter()
+            .filter(|sub| sub.program_source_id.eq(program_source_id))
+            .map(|sub| sub.id)
+            .collect();
         self.subscriptions
-            .retain(|sub| sub.program_source_id.ne(program_source_id))
+            .retain(|sub| sub.program_source_id.ne(program_source_id));
+        for id in removed {
+            self.retract_subscription_facts(id);
+        }
     }
 }
 
@@ -202,7 +1017,7 @@ mod tests {
             }
         );
 
-        db.retract("fox is $");
+        db.retract("fox is $").unwrap();
 
         let q4 = db.select(&vec!["$x is red".to_string()]);
         let mut q4 = q4.iter();
@@ -216,4 +1031,605 @@ mod tests {
             }
         );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn typed_term_tests() {
+        let mut db = Database::new();
+        db.claim(Fact::from_string("#0cv program 3 at 10 20.5"));
+        db.claim(Fact::from_string("#1 lamp is true"));
+
+        let q1 = db.select(&vec!["$ program $id at 10.0 $y".to_string()]);
+        assert_eq!(
+            q1,
+            vec![QueryResult {
+                result: vec![
+                    QueryResultVariable {
+                        variable_name: "id".to_string(),
+                        term: Term::Integer(3)
+                    },
+                    QueryResultVariable {
+                        variable_name: "y".to_string(),
+                        term: Term::Float(20.5)
+                    }
+                ]
+            }]
+        );
+        assert!(db
+            .select(&vec!["$ program \"3\" at %".to_string()])
+            .is_empty());
+        assert_eq!(db.select(&vec!["$ lamp is true".to_string()]).len(), 1);
+    }
+
+    #[test]
+    fn indexed_select_benchmark() {
+        fn collect_solutions_unindexed(
+            db: &Database,
+            query: &[&Fact],
+            env: &QueryResult,
+        ) -> Vec<QueryResult> {
+            if query.is_empty() {
+                return vec![env.clone()];
+            }
+            let mut solutions: Vec<QueryResult> = vec![];
+            for f in db.facts.values() {
+                let mut new_env: QueryResult = env.clone();
+                if Database::fact_match(query[0], f, &mut new_env) {
+                    solutions.extend(collect_solutions_unindexed(db, &query[1..], &new_env));
+                }
+            }
+            solutions
+        }
+
+        let mut db = Database::new();
+        for i in 0..10_000 {
+            db.claim(Fact::from_string(&format!(
+                "#1 item {} has value {}",
+                i,
+                i * 2
+            )));
+            if i % 100 == 0 {
+                db.claim(Fact::from_string(&format!("#2 item {} is selected", i)));
+            }
+        }
+        let query_parts = vec![
+            "$ item $i is selected".to_string(),
+            "$ item $i has value $v".to_string(),
+        ];
+        let query: Vec<Fact> = query_parts.iter().map(|p| Fact::from_string(p)).collect();
+        let query: Vec<&Fact> = query.iter().collect();
+
+        let start = std::time::Instant::now();
+        let indexed = db.select(&query_parts);
+        let indexed_duration = start.elapsed();
+
+        let start = std::time::Instant::now();
+        let unindexed = collect_solutions_unindexed(&db, &query, &QueryResult { result: vec![] });
+        let unindexed_duration = start.elapsed();
+
+        println!(
+            "10k facts: indexed select {:?}, full scan {:?}",
+            indexed_duration, unindexed_duration
+        );
+        assert_eq!(indexed.len(), 100);
+        assert_eq!(indexed, unindexed);
+
+        db.retract("#2 item $ is selected").unwrap();
+        assert!(db.select(&query_parts).is_empty());
+        assert_eq!(
+            db.select(&vec!["$ item 5000 has value $v".to_string()])
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn planned_select_tests() {
+        let mut db = Database::new();
+        for i in 0..200 {
+            db.claim(Fact::from_string(&format!("#1 thing{} is red", i)));
+        }
+        db.claim(Fact::from_string("#2 thing7 is a fox"));
+        db.claim(Fact::from_string("#2 thing8 is a fox"));
+        db.claim(Fact::from_string("#3 thing8 is red"));
+
+        let query_parts = vec!["$ $x is red".to_string(), "$ $x is a fox".to_string()];
+        let plan = db.explain(&query_parts).unwrap();
+        assert_eq!(
+            plan.steps
+                .iter()
+                .map(|step| step.clause_index)
+                .collect::<Vec<usize>>(),
+            vec![1, 0]
+        );
+        assert_eq!(plan.steps[1].bound_variables, vec!["x".to_string()]);
+        println!("{}", plan);
+
+        let x = |name: &str| QueryResult {
+            result: vec![QueryResultVariable {
+                variable_name: "x".to_string(),
+                term: Term::Text(name.to_string()),
+            }],
+        };
+        assert_eq!(
+            db.select(&query_parts),
+            vec![x("thing7"), x("thing8"), x("thing8")]
+        );
+
+        let results = db.select(&vec![
+            "$ $x is $color".to_string(),
+            "#2 $x is a fox".to_string(),
+        ]);
+        assert_eq!(results.len(), 3);
+        assert!(results
+            .iter()
+            .all(|r| r.result[0].variable_name == "x" && r.result[1].variable_name == "color"));
+        assert!(db.explain(&vec!["\"unterminated".to_string()]).is_err());
+    }
+
+    #[test]
+    fn subscription_result_changes() {
+        let lua = mlua::Lua::new();
+        let callback = lua.create_registry_value(0).unwrap();
+        let mut sub = Subscription::new(
+            &"4".to_string(),
+            &vec!["$ $someone is a fox".to_string()],
+            callback,
+        );
+        let mut db = Database::new();
+
+        let changes = sub.update_results(db.select(&sub.query_parts)).unwrap();
+        assert!(changes.results.is_empty() && changes.added.is_empty());
+        assert_eq!(sub.update_results(db.select(&sub.query_parts)), None);
+
+        db.claim(Fact::from_string("#6 you is a fox"));
+        db.claim(Fact::from_string("#7 me is a fox"));
+        let changes = sub.update_results(db.select(&sub.query_parts)).unwrap();
+        assert_eq!(changes.added.len(), 2);
+        assert!(changes.removed.is_empty());
+
+        db.retract("#7 %").unwrap();
+        db.claim(Fact::from_string("#7 me is a fox"));
+        assert_eq!(sub.update_results(db.select(&sub.query_parts)), None);
+
+        db.retract("#6 %").unwrap();
+        let changes = sub.update_results(db.select(&sub.query_parts)).unwrap();
+        assert_eq!(changes.results.len(), 1);
+        assert!(changes.added.is_empty());
+        assert_eq!(
+            changes.removed[0].result[0].term,
+            Term::Text("you".to_string())
+        );
+    }
+
+    #[test]
+    fn subscription_owned_facts() {
+        let lua = mlua::Lua::new();
+        let mut db = Database::new();
+        let sub_id = db.add_subscription(Subscription::new(
+            &"4".to_string(),
+            &vec!["$ $someone is a fox".to_string()],
+            lua.create_registry_value(0).unwrap(),
+        ));
+        db.claim(Fact::from_string("#4 fox counter"));
+        db.claim_for_subscription(sub_id, Fact::from_string("#4 you see a fox"));
+        db.claim_for_subscription(sub_id, Fact::from_string("#4 you see a red fox"));
+        assert_eq!(db.select(&vec!["#4 %".to_string()]).len(), 3);
+
+        db.retract_subscription_facts(sub_id);
+        assert_eq!(db.select(&vec!["#4 %".to_string()]).len(), 1);
+
+        db.claim_for_subscription(sub_id, Fact::from_string("#4 you see a fox"));
+        db.remove_subscriptions_by_program(&"4".to_string());
+        assert!(db.subscriptions.is_empty());
+        assert_eq!(db.select(&vec!["#4 %rest".to_string()]).len(), 1);
+    }
+
+    #[test]
+    fn negated_clause_tests() {
+        let mut db = Database::new();
+        db.claim(Fact::from_string("#6 you is a fox"));
+        db.claim(Fact::from_string("#7 me is a fox"));
+        db.claim(Fact::from_string("#1 me is red"));
+        db.claim(Fact::from_string("#1 not tired"));
+
+        let query_parts = vec!["not $ $p is red".to_string(), "$ $p is a fox".to_string()];
+        let plan = db.explain(&query_parts).unwrap();
+        assert_eq!(plan.steps[0].clause_index, 1);
+        assert_eq!(plan.steps[1].clause, "not $ $p is red");
+        assert_eq!(
+            db.select(&query_parts),
+            vec![QueryResult {
+                result: vec![QueryResultVariable {
+                    variable_name: "p".to_string(),
+                    term: Term::Text("you".to_string())
+                }]
+            }]
+        );
+
+        assert!(db
+            .select(&vec!["not $ $someone is a fox".to_string()])
+            .is_empty());
+        assert_eq!(
+            db.select(&vec!["not $ $someone is a wolf".to_string()]),
+            vec![QueryResult { result: vec![] }]
+        );
+        assert_eq!(db.select(&vec!["$ \"not\" tired".to_string()]).len(), 1);
+    }
+
+    #[test]
+    fn predicate_clause_tests() {
+        let mut db = Database::new();
+        db.claim(Fact::from_string("#0cv program 3 at 120.5 40"));
+        db.claim(Fact::from_string("#0cv program 7 at 300 80"));
+        db.claim(Fact::from_string("#0cv program 9 at 600 10"));
+        db.claim(Fact::from_string(
+            "#1 wish \"http://example.com\" would be printed",
+        ));
+        db.claim(Fact::from_string(
+            "#1 wish \"ftp://example.com\" would be printed",
+        ));
+
+        let ids = |query_parts: &[&str]| -> Vec<Term> {
+            db.select(&query_parts.iter().map(|p| p.to_string()).collect())
+                .into_iter()
+                .map(|r| r.result[0].term.clone())
+                .collect()
+        };
+        assert_eq!(
+            ids(&["$x < 300", "$ program $id at $x $y"]),
+            vec![Term::Integer(3)]
+        );
+        assert_eq!(
+            ids(&["$ program $id at $x $y", "$x < 300"]),
+            vec![Term::Integer(3)]
+        );
+        assert_eq!(
+            ids(&["$ program $id at $x $y", "$x >= 300"]),
+            vec![Term::Integer(7), Term::Integer(9)]
+        );
+        assert_eq!(
+            ids(&["$ program $id at $x $y", "$y between 10 40", "$id != 9"]),
+            vec![Term::Integer(3)]
+        );
+        assert_eq!(
+            ids(&["$ program $id at $x $y", "$id = 7.0"]),
+            vec![Term::Integer(7)]
+        );
+        assert_eq!(
+            ids(&["$ wish $url would be printed", "$url starts_with http"]),
+            vec![Term::Text("http://example.com".to_string())]
+        );
+        assert_eq!(
+            ids(&["$ wish $url would be printed", "$url matches \"^ftp:\""]),
+            vec![Term::Text("ftp://example.com".to_string())]
+        );
+
+        let plan = db
+            .explain(&vec![
+                "$x < 300".to_string(),
+                "$ program $id at $x $y".to_string(),
+            ])
+            .unwrap();
+        assert_eq!(plan.steps[1].clause, "$x < 300");
+        let err = db.explain(&vec!["$z < 300".to_string()]).unwrap_err();
+        assert_eq!(err.clause_index, 0);
+        assert!(db
+            .explain(&vec!["$ $x".to_string(), "$x matches \"(\"".to_string()])
+            .is_err());
+        let err = db
+            .explain(&vec!["$ $x $y".to_string(), "$x matches $y".to_string()])
+            .unwrap_err();
+        assert_eq!(err.clause_index, 1);
+        assert!(db
+            .explain(&vec!["$ $x".to_string(), "$x matches 5".to_string()])
+            .is_err());
+    }
+
+    #[test]
+    fn aggregate_clause_tests() {
+        let mut db = Database::new();
+        let query = |db: &Database, query_parts: &[&str]| -> Vec<Vec<Term>> {
+            db.select(&query_parts.iter().map(|p| p.to_string()).collect())
+                .into_iter()
+                .map(|r| r.result.into_iter().map(|v| v.term).collect())
+                .collect()
+        };
+        assert_eq!(
+            query(&db, &["$ program $id at %", "$n = count $id"]),
+            vec![vec![Term::Integer(0)]]
+        );
+        assert!(query(&db, &["$ program $id at $x $y", "$left = min $x"]).is_empty());
+
+        db.claim(Fact::from_string("#0cv program 3 at 120.5 40"));
+        db.claim(Fact::from_string("#0cv program 7 at 300 80"));
+        db.claim(Fact::from_string("#0cv program 9 at 600 10"));
+        db.claim(Fact::from_string("#3 wish 7 had color red"));
+        db.claim(Fact::from_string("#3 wish 9 had color red"));
+        db.claim(Fact::from_string("#3 wish 3 had color blue"));
+
+        assert_eq!(
+            query(&db, &["$ program $id at %", "$n = count $id"]),
+            vec![vec![Term::Integer(3)]]
+        );
+        assert_eq!(
+            query(
+                &db,
+                &[
+                    "$ program $ at $x $",
+                    "$left = min $x",
+                    "$right = max $x",
+                    "$total = sum $x"
+                ]
+            ),
+            vec![vec![
+                Term::Float(120.5),
+                Term::Integer(600),
+                Term::Float(1020.5)
+            ]]
+        );
+        assert_eq!(
+            query(
+                &db,
+                &[
+                    "$ wish $id had color $color",
+                    "$ids = collect $id",
+                    "$n = count $id"
+                ]
+            ),
+            vec![
+                vec![
+                    Term::Text("red".to_string()),
+                    Term::Json(serde_json::json!([7, 9])),
+                    Term::Integer(2)
+                ],
+                vec![
+                    Term::Text("blue".to_string()),
+                    Term::Json(serde_json::json!([3])),
+                    Term::Integer(1)
+                ]
+            ]
+        );
+
+        let plan = db
+            .explain(&vec![
+                "$n = count $id".to_string(),
+                "$ program $id at %".to_string(),
+            ])
+            .unwrap();
+        assert_eq!(plan.steps[1].clause, "$n = count $id");
+        assert!(db
+            .explain(&vec!["$ $x".to_string(), "$x = count $x".to_string()])
+            .is_err());
+        assert!(db
+            .explain(&vec!["$ $x".to_string(), "$n = count $y".to_string()])
+            .is_err());
+    }
+
+    #[test]
+    fn history_tests() {
+        let mut db = Database::new();
+        let flicker = vec!["#0cv program $id at %".to_string()];
+        db.claim(Fact::from_string("#0cv program 3 at 0 0"));
+        db.claim(Fact::from_string("#1 fox is red"));
+        let first_tick = db.tick();
+        db.advance_tick();
+        db.retract("#0cv %").unwrap();
+        db.advance_tick();
+        db.claim(Fact::from_string("#0cv program 3 at 5 5"));
+        db.retract("#1 %").unwrap();
+        let last_tick = db.tick();
+
+        assert_eq!(db.select_as_of(first_tick, &flicker).unwrap().len(), 1);
+        assert!(db
+            .select_as_of(first_tick + 1, &flicker)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            db.select_as_of(first_tick, &vec!["#1 %".to_string()])
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(
+            db.select_as_of(last_tick, &flicker).unwrap(),
+            db.select(&flicker)
+        );
+
+        let changes = db.changes_between(first_tick, last_tick);
+        assert_eq!(
+            changes
+                .iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<String>>(),
+            vec![
+                "1 retract #0cv program 3 at 0 0",
+                "2 claim #0cv program 3 at 5 5",
+                "2 retract #1 fox is red",
+            ]
+        );
+        assert_eq!(changes[2].owner, Some("1".to_string()));
+
+        db.set_history_capacity(2);
+        assert_eq!(db.changes_between(0, last_tick).len(), 2);
+        assert_eq!(
+            db.select_as_of(first_tick, &flicker).unwrap_err(),
+            HistoryUnavailable {
+                requested_tick: first_tick,
+                oldest_tick: 1
+            }
+        );
+        assert!(db.select_as_of(1, &flicker).is_ok());
+    }
+
+    #[test]
+    fn expiring_facts() {
+        let mut db = Database::new();
+        let count = |db: &Database| db.select(&vec!["$ blink %".to_string()]).len();
+        db.claim_with_expiry(Fact::from_string("#5 blink once"), Expiry::Ticks(1));
+        db.claim_with_expiry(Fact::from_string("#5 blink twice"), Expiry::Ticks(2));
+        db.claim_with_expiry(
+            Fact::from_string("#5 blink slowly"),
+            Expiry::After(Duration::from_secs(3600)),
+        );
+        db.claim_with_expiry(
+            Fact::from_string("#5 blink now"),
+            Expiry::After(Duration::ZERO),
+        );
+        db.claim(Fact::from_string("#5 blink forever"));
+        assert_eq!(count(&db), 5);
+
+        db.advance_tick();
+        assert_eq!(count(&db), 3);
+        db.retract("#5 blink slowly").unwrap();
+        db.advance_tick();
+        assert_eq!(
+            db.select(&vec!["$ blink $how".to_string()])[0].result[0].term,
+            Term::Text("forever".to_string())
+        );
+        assert_eq!(count(&db), 1);
+        assert!(db.expiring_facts.is_empty());
+    }
+
+    #[test]
+    fn fact_provenance() {
+        let mut db = Database::new();
+        db.claim(Fact::from_string("#1 fox is red"));
+        db.advance_tick();
+        db.claim_from(
+            FactSource::Vision,
+            Fact::from_string("#0cv program 3 at 0 0"),
+        );
+        db.claim_for_subscription(7, Fact::from_string("#4 you see a fox"));
+        db.claim(Fact::from_string("nobody claimed this"));
+
+        let provenance = db.provenance("$ %").unwrap();
+        let metadata: Vec<(Option<&str>, Option<SubscriptionId>, u64, FactSource)> = provenance
+            .iter()
+            .map(|(_, m)| {
+                (
+                    m.owner.as_deref(),
+                    m.subscription_id,
+                    m.created_tick,
+                    m.source,
+                )
+            })
+            .collect();
+        assert_eq!(
+            metadata,
+            vec![
+                (Some("1"), None, 0, FactSource::Local),
+                (Some("0cv"), None, 1, FactSource::Vision),
+                (Some("4"), Some(7), 1, FactSource::Local),
+                (None, None, 1, FactSource::Local),
+            ]
+        );
+        assert_eq!(provenance[2].0, Fact::from_string("#4 you see a fox"));
+
+        db.retract_subscription_facts(7);
+        assert!(db.provenance("#4 %").unwrap().is_empty());
+        assert_eq!(db.metadata.len(), 3);
+        assert!(db.provenance("\"oops").is_err());
+    }
+
+    #[test]
+    fn transactions() {
+        let mut db = Database::new();
+        let graphics = vec!["$ wish $ had graphics $g".to_string()];
+        db.claim(Fact::from_string("#3 wish you had graphics old"));
+
+        db.begin();
+        db.retract("#3 wish you had graphics $").unwrap();
+        db.claim(Fact::from_string("#3 wish you had graphics new"));
+        db.claim_for_subscription(1, Fact::from_string("#3 clock ticked"));
+        assert!(db.in_transaction());
+        assert_eq!(
+            db.select(&graphics)[0].result[0].term,
+            Term::Text("old".to_string())
+        );
+        db.commit();
+        assert_eq!(
+            db.select(&graphics)[0].result[0].term,
+            Term::Text("new".to_string())
+        );
+        assert_eq!(db.select(&graphics).len(), 1);
+
+        db.begin();
+        db.retract_subscription_facts(1);
+        db.claim(Fact::from_string("#3 wish you had graphics newer"));
+        db.rollback();
+        assert!(!db.in_transaction());
+        assert_eq!(db.select(&graphics).len(), 1);
+        assert_eq!(db.select(&vec!["$ clock ticked".to_string()]).len(), 1);
+    }
+
+    #[test]
+    fn deduplicated_facts() {
+        let mut db = Database::new();
+        db.claim(Fact::from_string("#1 fox is red"));
+        db.claim(Fact::from_string("#1 fox is red"));
+        let reds = vec!["$ $animal is red".to_string()];
+        assert_eq!(db.select(&reds).len(), 2);
+
+        db.set_deduplicate(true);
+        assert_eq!(db.select(&reds).len(), 1);
+        db.claim_for_subscription(4, Fact::from_string("#1 fox is red"));
+        db.claim_for_subscription(5, Fact::from_string("#1 fox is red"));
+        db.claim(Fact::from_string("#2 fox is red"));
+        db.claim_with_expiry(Fact::from_string("#1 fox is 1.0"), Expiry::Ticks(1));
+        db.claim(Fact::from_string("#1 fox is 1"));
+        assert_eq!(db.select(&reds).len(), 2);
+        assert_eq!(db.select(&vec!["$ fox is 1".to_string()]).len(), 1);
+        assert_eq!(db.provenance("#1 fox is red").unwrap().len(), 4);
+
+        // Each subscription and expiry only takes back its own claim
+        db.retract_subscription_facts(4);
+        db.advance_tick();
+        assert_eq!(db.select(&reds).len(), 2);
+        assert_eq!(db.select(&vec!["$ fox is 1".to_string()]).len(), 1);
+
+        // A retract takes back every claim
+        db.retract("#1 fox is red").unwrap();
+        assert_eq!(db.select(&reds).len(), 1);
+        assert_eq!(db.provenance("#1 %").unwrap().len(), 1);
+        db.retract("#2 %").unwrap();
+        assert!(db.select(&reds).is_empty());
+        assert_eq!(db.duplicates.len(), 1);
+    }
+
+    #[test]
+    fn scoped_retract() {
+        let mut db = Database::new();
+        db.claim(Fact::from_string("#0cv program 3 at 0 0"));
+        db.claim(Fact::from_string("#1 fox is red"));
+        db.claim(Fact::from_string("#2 fox is red"));
+        db.claim(Fact::from_string("#2 fox is quick"));
+
+        assert_eq!(db.retract_owned("2", "$ fox is red"), Ok(1));
+        assert_eq!(db.retract_owned("2", "$ %"), Ok(1));
+        assert_eq!(db.retract_owned("2", "$ %"), Ok(0));
+        assert_eq!(db.retract_owned("1", "#0cv %"), Ok(0));
+        assert_eq!(db.select(&vec!["$ %".to_string()]).len(), 2);
+
+        db.begin();
+        assert_eq!(db.retract("$ %"), Ok(2));
+        assert_eq!(db.select(&vec!["$ %".to_string()]).len(), 2);
+        db.commit();
+        assert!(db.select(&vec!["$ %".to_string()]).is_empty());
+
+        // The count covers what the transaction itself claimed and retracted
+        db.claim(Fact::from_string("#3 old"));
+        db.begin();
+        db.claim(Fact::from_string("#3 tmp"));
+        assert_eq!(db.retract_owned("3", "$ %"), Ok(2));
+        assert_eq!(db.retract_owned("3", "$ %"), Ok(0));
+        db.claim_for_subscription(9, Fact::from_string("#3 seen"));
+        db.retract_subscription_facts(9);
+        assert_eq!(db.retract("#3 seen"), Ok(0));
+        db.commit();
+        assert!(db.select(&vec!["$ %".to_string()]).is_empty());
+
+        // A malformed pattern is an error rather than a panic
+        assert!(db.retract("fox is \"red").is_err());
+        assert!(db.retract_owned("1", "fox is \"red").is_err());
+    }
+}
@@ -1,9 +1,16 @@
-#[derive(Eq, PartialEq, Clone, Debug)]
+use std::fmt;
+
+#[derive(Clone, Debug)]
 pub enum Term {
     Text(String),
     Id(String),
     Variable(String),
     Postfix(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Json(serde_json::Value),
+    Blob(Vec<u8>),
 }
 impl Term {
     fn new(input: &str) -> Term {
@@ -12,47 +19,332 @@ impl Term {
                 '$' => Term::Variable(input[1..].to_string()),
                 '%' => Term::Postfix(input[1..].to_string()),
                 '#' => Term::Id(input[1..].to_string()),
-                _ => Term::Text(input[..].to_string()),
+                _ => Self::new_literal(input),
             },
             None => Term::Text("".to_string()),
         }
     }
 
+    fn new_literal(input: &str) -> Term {
+        match input {
+            "true" => return Term::Boolean(true),
+            "false" => return Term::Boolean(false),
+            _ => {}
+        }
+        // Only plain decimal notation counts as a number so words like "inf" stay text.
+        let looks_numeric = input.chars().any(|c| c.is_ascii_digit())
+            && input
+                .chars()
+                .all(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E'));
+        if looks_numeric {
+            if let Ok(i) = input.parse::<i64>() {
+                return Term::Integer(i);
+            }
+            if let Ok(f) = input.parse::<f64>() {
+                return Term::Float(f);
+            }
+        }
+        Term::Text(input.to_string())
+    }
+
+    /// The numeric value of an `Integer` or `Float` term.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Term::Integer(i) => Some(*i as f64),
+            Term::Float(f) => Some(*f),
+            _ => None,
+        }
+    }
+
     pub fn to_string(&self) -> String {
         match &self {
             Term::Text(text) => text.to_owned(),
             Term::Id(text) => "#".to_string() + &text,
             Term::Variable(text) => "$".to_string() + &text,
             Term::Postfix(text) => "%".to_string() + &text,
+            Term::Integer(i) => i.to_string(),
+            Term::Float(f) => format!("{:?}", f),
+            Term::Boolean(b) => b.to_string(),
+            Term::Json(value) => value.to_string(),
+            Term::Blob(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+        }
+    }
+
+    /// Like `to_string`, but quotes text that would not survive `Fact::from_string` as a
+    /// single text term (whitespace, quotes, backslashes, a leading `$`/`%`/`#`, "", or text
+    /// that would otherwise read back as a number or boolean). JSON and blob terms are written
+    /// quoted and so read back as text.
+    pub fn to_fact_string(&self) -> String {
+        match &self {
+            Term::Text(_) | Term::Json(_) | Term::Blob(_) => {
+                let text = self.to_string();
+                if !matches!(self, Term::Text(_)) || Self::needs_quotes(&text) {
+                    Self::quote(&text)
+                } else {
+                    text
+                }
+            }
+            _ => self.to_string(),
+        }
+    }
+
+    fn quote(text: &str) -> String {
+        let mut quoted = String::with_capacity(text.len() + 2);
+        quoted.push('"');
+        for c in text.chars() {
+            match c {
+                '"' => quoted.push_str("\\\""),
+                '\\' => quoted.push_str("\\\\"),
+                '\n' => quoted.push_str("\\n"),
+                '\t' => quoted.push_str("\\t"),
+                '\r' => quoted.push_str("\\r"),
+                _ => quoted.push(c),
+            }
+        }
+        quoted.push('"');
+        quoted
+    }
+
+    fn needs_quotes(text: &str) -> bool {
+        match text.chars().nth(0) {
+            None | Some('$') | Some('%') | Some('#') => true,
+            Some(_) => {
+                text.chars()
+                    .any(|c| c.is_whitespace() || c == '"' || c == '\\')
+                    || !matches!(Self::new_literal(text), Term::Text(_))
+            }
+        }
+    }
+}
+impl PartialEq for Term {
+    /// Numbers compare by value regardless of representation, so `1.0` equals `1`.
+    fn eq(&self, other: &Term) -> bool {
+        match (self, other) {
+            (Term::Text(a), Term::Text(b))
+            | (Term::Id(a), Term::Id(b))
+            | (Term::Variable(a), Term::Variable(b))
+            | (Term::Postfix(a), Term::Postfix(b)) => a == b,
+            (Term::Integer(a), Term::Integer(b)) => a == b,
+            (Term::Integer(_), Term::Float(_)) | (Term::Float(_), _) => {
+                match (self.as_f64(), other.as_f64()) {
+                    (Some(a), Some(b)) => a == b,
+                    _ => false,
+                }
+            }
+            (Term::Boolean(a), Term::Boolean(b)) => a == b,
+            (Term::Json(a), Term::Json(b)) => a == b,
+            (Term::Blob(a), Term::Blob(b)) => a == b,
+            _ => false,
         }
     }
 }
 
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FactParseError {
+    /// Character offset into the input where the problem was found.
+    pub position: usize,
+    pub message: String,
+}
+impl fmt::Display for FactParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+impl std::error::Error for FactParseError {}
+
 // #[derive(Copy, Clone)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Fact {
     pub terms: Vec<Term>,
 }
 impl Fact {
+    /// Parses a fact that is known to be well formed, like a literal in a test.
+    /// Use `Fact::parse` for anything else.
+    #[cfg(test)]
     pub fn from_string(input: &str) -> Fact {
-        Fact {
-            terms: input
-                .split_whitespace() // TODO: split on quotes here
-                .map(|chunk| Term::new(chunk))
-                .collect(),
+        match Self::parse(input) {
+            Ok(fact) => fact,
+            Err(e) => panic!("invalid fact {:?}: {}", input, e),
         }
     }
 
+    /// Splits `input` into terms on whitespace. A term wrapped in double quotes is always
+    /// text and may contain whitespace and the escapes `\"`, `\\`, `\n`, `\t` and `\r`.
+    pub fn parse(input: &str) -> Result<Fact, FactParseError> {
+        let mut terms: Vec<Term> = vec![];
+        let mut chars = input.chars().enumerate().peekable();
+        while let Some((start, c)) = chars.next() {
+            if c.is_whitespace() {
+                continue;
+            }
+            if c == '"' {
+                let mut text = String::new();
+                let mut closed = false;
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        '"' => {
+                            closed = true;
+                            break;
+                        }
+                        '\\' => match chars.next() {
+                            Some((_, '"')) => text.push('"'),
+                            Some((_, '\\')) => text.push('\\'),
+                            Some((_, 'n')) => text.push('\n'),
+                            Some((_, 't')) => text.push('\t'),
+                            Some((_, 'r')) => text.push('\r'),
+                            Some((_, other)) => {
+                                return Err(FactParseError {
+                                    position: i,
+                                    message: format!("unknown escape sequence \\{}", other),
+                                })
+                            }
+                            None => break,
+                        },
+                        _ => text.push(c),
+                    }
+                }
+                if !closed {
+                    return Err(FactParseError {
+                        position: start,
+                        message: "unterminated quoted string".to_string(),
+                    });
+                }
+                if let Some((i, c)) = chars.peek() {
+                    if !c.is_whitespace() {
+                        return Err(FactParseError {
+                            position: *i,
+                            message: "expected whitespace after closing quote".to_string(),
+                        });
+                    }
+                }
+                terms.push(Term::Text(text));
+            } else {
+                let mut chunk = String::new();
+                chunk.push(c);
+                while let Some((i, c)) = chars.peek() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    if *c == '"' {
+                        return Err(FactParseError {
+                            position: *i,
+                            message: "unexpected quote inside term".to_string(),
+                        });
+                    }
+                    chunk.push(*c);
+                    chars.next();
+                }
+                terms.push(Term::new(&chunk));
+            }
+        }
+        Ok(Fact { terms })
+    }
+
     pub fn from_terms(input_terms: &[Term]) -> Fact {
         Fact {
             terms: input_terms.to_owned(),
         }
     }
 
+    /// The id the fact starts with, which is the program that claimed it.
+    pub fn owner(&self) -> Option<&str> {
+        match self.terms.first() {
+            Some(Term::Id(owner)) => Some(owner),
+            _ => None,
+        }
+    }
+
     pub fn to_string(&self) -> String {
         self.terms
             .iter()
-            .map(|t| t.to_string())
+            .map(|t| t.to_fact_string())
             .collect::<Vec<String>>()
             .join(" ")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_quoted_terms() {
+        let fact = Fact::from_string(
+            r#"#3 wish "http://192.168.1.34:8000/smiley.png" would be "thermal printed""#,
+        );
+        assert_eq!(
+            fact.terms,
+            vec![
+                Term::Id("3".to_string()),
+                Term::Text("wish".to_string()),
+                Term::Text("http://192.168.1.34:8000/smiley.png".to_string()),
+                Term::Text("would".to_string()),
+                Term::Text("be".to_string()),
+                Term::Text("thermal printed".to_string()),
+            ]
+        );
+
+        let fact = Fact::from_string(r#"say "she said \"hi\"\n" "" "$not a variable""#);
+        assert_eq!(
+            fact.terms,
+            vec![
+                Term::Text("say".to_string()),
+                Term::Text("she said \"hi\"\n".to_string()),
+                Term::Text("".to_string()),
+                Term::Text("$not a variable".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trip_through_to_string() {
+        let fact = Fact::from_terms(&[
+            Term::Id("0".to_string()),
+            Term::Variable("x".to_string()),
+            Term::Text("back\\slash and \"quotes\"".to_string()),
+            Term::Text("#hash".to_string()),
+            Term::Text("".to_string()),
+            Term::Text("plain".to_string()),
+        ]);
+        assert_eq!(Fact::parse(&fact.to_string()).unwrap(), fact);
+        assert_eq!(Fact::from_string("fox is red").to_string(), "fox is red");
+    }
+
+    #[test]
+    fn parse_errors_report_position() {
+        assert_eq!(
+            Fact::parse(r#"wish "unterminated"#).unwrap_err(),
+            FactParseError {
+                position: 5,
+                message: "unterminated quoted string".to_string()
+            }
+        );
+        assert_eq!(Fact::parse(r#"a "b"c"#).unwrap_err().position, 5);
+        assert_eq!(Fact::parse(r#"ab"c""#).unwrap_err().position, 2);
+        assert_eq!(Fact::parse(r#""bad \q escape""#).unwrap_err().position, 5);
+    }
+
+    #[test]
+    fn parse_typed_terms() {
+        let fact = Fact::from_string("#0cv program 3 at -1.5 2e3 true \"4\" inf");
+        assert_eq!(
+            fact.terms,
+            vec![
+                Term::Id("0cv".to_string()),
+                Term::Text("program".to_string()),
+                Term::Integer(3),
+                Term::Text("at".to_string()),
+                Term::Float(-1.5),
+                Term::Float(2000.0),
+                Term::Boolean(true),
+                Term::Text("4".to_string()),
+                Term::Text("inf".to_string()),
+            ]
+        );
+        assert_eq!(Fact::parse(&fact.to_string()).unwrap(), fact);
+        assert_eq!(Term::Float(1.0), Term::Integer(1));
+        assert_eq!(fact.owner(), Some("0cv"));
+        assert_eq!(Fact::from_string("fox is red").owner(), None);
+        assert_ne!(Term::Text("1".to_string()), Term::Integer(1));
+    }
+}
@@ -0,0 +1,136 @@
+use crate::fact::Fact;
+use crate::index::FactId;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::time::SystemTime;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HistoryEventKind {
+    Claim,
+    Retract,
+}
+
+#[derive(Clone, Debug)]
+pub struct HistoryEvent {
+    pub tick: u64,
+    pub time: SystemTime,
+    pub kind: HistoryEventKind,
+    pub fact_id: FactId,
+    pub fact: Fact,
+    pub owner: Option<String>,
+}
+impl HistoryEvent {
+    pub fn to_string(&self) -> String {
+        let kind = match self.kind {
+            HistoryEventKind::Claim => "claim",
+            HistoryEventKind::Retract => "retract",
+        };
+        format!("{} {} {}", self.tick, kind, self.fact.to_string())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryUnavailable {
+    pub requested_tick: u64,
+    /// The earliest tick the history still goes back to.
+    pub oldest_tick: u64,
+}
+impl fmt::Display for HistoryUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "history for tick {} was dropped, the oldest kept is tick {}",
+            self.requested_tick, self.oldest_tick
+        )
+    }
+}
+impl std::error::Error for HistoryUnavailable {}
+
+/// The most recent claims and retracts, numbered by tick. A tick is whatever the caller
+/// advances it on; the app advances it once per frame.
+pub struct History {
+    events: VecDeque<HistoryEvent>,
+    capacity: usize,
+    tick: u64,
+    /// Every event after this tick is still kept.
+    complete_after: u64,
+}
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        History {
+            events: VecDeque::new(),
+            capacity,
+            tick: 0,
+            complete_after: 0,
+        }
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn advance_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.drop_oldest();
+    }
+
+    pub fn record(&mut self, kind: HistoryEventKind, fact_id: FactId, fact: &Fact) {
+        let owner = fact.owner().map(str::to_owned);
+        self.events.push_back(HistoryEvent {
+            tick: self.tick,
+            time: SystemTime::now(),
+            kind,
+            fact_id,
+            fact: fact.clone(),
+            owner,
+        });
+        self.drop_oldest();
+    }
+
+    fn drop_oldest(&mut self) {
+        while self.events.len() > self.capacity {
+            if let Some(event) = self.events.pop_front() {
+                self.complete_after = self.complete_after.max(event.tick);
+            }
+        }
+    }
+
+    /// The events after tick `from` up to and including tick `to`, oldest first.
+    pub fn changes_between(&self, from: u64, to: u64) -> Vec<&HistoryEvent> {
+        self.events
+            .iter()
+            .filter(|event| event.tick > from && event.tick <= to)
+            .collect()
+    }
+
+    /// Undoes the events after `tick` on `facts`, leaving them as they were at the end of
+    /// that tick.
+    pub fn rewind(
+        &self,
+        facts: &mut BTreeMap<FactId, Fact>,
+        tick: u64,
+    ) -> Result<(), HistoryUnavailable> {
+        if tick < self.complete_after {
+            return Err(HistoryUnavailable {
+                requested_tick: tick,
+                oldest_tick: self.complete_after,
+            });
+        }
+        for event in self.events.iter().rev().take_while(|e| e.tick > tick) {
+            match event.kind {
+                HistoryEventKind::Claim => {
+                    facts.remove(&event.fact_id);
+                }
+                HistoryEventKind::Retract => {
+                    facts.insert(event.fact_id, event.fact.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+use crate::fact::{Fact, Term};
+use std::collections::{BTreeSet, HashMap};
+
+pub type FactId = u64;
+
+/// A hashable stand-in for a constant term. Numbers are normalized so that terms that
+/// compare equal (`1` and `1.0`) share a key.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum TermKey {
+    Text(String),
+    Id(String),
+    Integer(i64),
+    Float(u64),
+    Boolean(bool),
+    Json(String),
+    Blob(Vec<u8>),
+}
+impl TermKey {
+    /// `None` for variables and postfixes, which match anything.
+    pub fn new(term: &Term) -> Option<TermKey> {
+        match term {
+            Term::Variable(_) | Term::Postfix(_) => None,
+            Term::Text(text) => Some(TermKey::Text(text.to_owned())),
+            Term::Id(text) => Some(TermKey::Id(text.to_owned())),
+            Term::Integer(i) => Some(TermKey::Integer(*i)),
+            Term::Float(f) => {
+                if f.fract() == 0.0 && *f >= i64::MIN as f64 && *f <= i64::MAX as f64 {
+                    Some(TermKey::Integer(*f as i64))
+                } else {
+                    Some(TermKey::Float(f.to_bits()))
+                }
+            }
+            Term::Boolean(b) => Some(TermKey::Boolean(*b)),
+            Term::Json(value) => Some(TermKey::Json(value.to_string())),
+            Term::Blob(bytes) => Some(TermKey::Blob(bytes.to_owned())),
+        }
+    }
+}
+
+/// How many terms a fact matching a pattern can have.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Arity {
+    Exact(usize),
+    /// The pattern ends in a postfix, which needs at least one term to match.
+    AtLeast(usize),
+}
+impl Arity {
+    pub fn of_pattern(pattern: &Fact) -> Arity {
+        match pattern.terms.last() {
+            Some(Term::Postfix(_)) => Arity::AtLeast(pattern.terms.len()),
+            _ => Arity::Exact(pattern.terms.len()),
+        }
+    }
+}
+
+/// Secondary indexes over the facts in a `Database`: by number of terms and by the
+/// constant term at each position (which covers the leading `Id` of a claim).
+pub struct FactIndex {
+    by_arity: HashMap<usize, BTreeSet<FactId>>,
+    by_term: HashMap<(usize, TermKey), BTreeSet<FactId>>,
+    /// How many different constant terms appear at each position, for estimating how
+    /// selective a bound variable at that position is.
+    distinct_terms: HashMap<usize, usize>,
+}
+impl FactIndex {
+    pub fn new() -> Self {
+        FactIndex {
+            by_arity: HashMap::new(),
+            by_term: HashMap::new(),
+            distinct_terms: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, id: FactId, fact: &Fact) {
+        self.by_arity
+            .entry(fact.terms.len())
+            .or_insert_with(BTreeSet::new)
+            .insert(id);
+        for (position, term) in fact.terms.iter().enumerate() {
+            if let Some(key) = TermKey::new(term) {
+                let ids = self
+                    .by_term
+                    .entry((position, key))
+                    .or_insert_with(BTreeSet::new);
+                if ids.is_empty() {
+                    *self.distinct_terms.entry(position).or_insert(0) += 1;
+                }
+                ids.insert(id);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, id: FactId, fact: &Fact) {
+        if let Some(ids) = self.by_arity.get_mut(&fact.terms.len()) {
+            ids.remove(&id);
+            if ids.is_empty() {
+                self.by_arity.remove(&fact.terms.len());
+            }
+        }
+        for (position, term) in fact.terms.iter().enumerate() {
+            if let Some(key) = TermKey::new(term) {
+                let index_key = (position, key);
+                if let Some(ids) = self.by_term.get_mut(&index_key) {
+                    ids.remove(&id);
+                    if ids.is_empty() {
+                        self.by_term.remove(&index_key);
+                        if let Some(count) = self.distinct_terms.get_mut(&position) {
+                            *count -= 1;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// The ids of facts that could match a pattern with the given arity and constant terms,
+    /// in claim order. Candidates still have to be checked against the full pattern.
+    pub fn candidates(&self, arity: Arity, constants: &[(usize, TermKey)]) -> Vec<FactId> {
+        let mut smallest: Option<&BTreeSet<FactId>> = None;
+        for (position, key) in constants.iter() {
+            match self.by_term.get(&(*position, key.clone())) {
+                None => return vec![],
+                Some(ids) => {
+                    if smallest.map_or(true, |s| ids.len() < s.len()) {
+                        smallest = Some(ids);
+                    }
+                }
+            }
+        }
+        match arity {
+            Arity::Exact(n) => match (smallest, self.by_arity.get(&n)) {
+                (_, None) => vec![],
+                (Some(s), Some(a)) if s.len() < a.len() => s.iter().cloned().collect(),
+                (_, Some(a)) => a.iter().cloned().collect(),
+            },
+            Arity::AtLeast(n) => match smallest {
+                Some(s) => s.iter().cloned().collect(),
+                None => {
+                    let mut ids: Vec<FactId> = self
+                        .by_arity
+                        .iter()
+                        .filter(|(arity, _)| **arity >= n)
+                        .flat_map(|(_, ids)| ids.iter().cloned())
+                        .collect();
+                    ids.sort();
+                    ids
+                }
+            },
+        }
+    }
+
+    /// Roughly how many facts match a pattern with the given arity and constant terms,
+    /// and variables at `bound_positions` that will be bound to unknown values.
+    pub fn estimate(
+        &self,
+        arity: Arity,
+        constants: &[(usize, TermKey)],
+        bound_positions: &[usize],
+    ) -> usize {
+        let arity_count: usize = match arity {
+            Arity::Exact(n) => self.by_arity.get(&n).map_or(0, |ids| ids.len()),
+            Arity::AtLeast(n) => self
+                .by_arity
+                .iter()
+                .filter(|(arity, _)| **arity >= n)
+                .map(|(_, ids)| ids.len())
+                .sum(),
+        };
+        let mut estimate = arity_count;
+        for (position, key) in constants.iter() {
+            let count = self
+                .by_term
+                .get(&(*position, key.clone()))
+                .map_or(0, |ids| ids.len());
+            estimate = estimate.min(count);
+        }
+        for position in bound_positions.iter() {
+            let distinct = self.distinct_terms.get(position).cloned().unwrap_or(1);
+            estimate = estimate.min(arity_count / distinct.max(1) + 1);
+        }
+        estimate
+    }
+}
@@ -1,4 +1,4 @@
-use crate::database::Database;
+use crate::database::{Database, FactSource};
 use crate::fact::{Fact, Term};
 
 use std::error::Error;
@@ -18,7 +18,11 @@ use serde_json::{Result, Value};
 
 pub mod database;
 pub mod fact;
+pub mod history;
 pub mod illumination;
+pub mod index;
+pub mod persistence;
+pub mod query;
 pub mod source_code;
 pub mod vision;
 
@@ -27,6 +31,7 @@ use lazy_static::lazy_static;
 struct Model {
     vision_handle: JoinHandle<()>,
     static_db: &'static Mutex<Database>,
+    source_code_manager: source_code::SourceCodeManager,
     main_frame: Arc<Mutex<Mat>>,
     rx: mpsc::Receiver<Vec<crate::vision::SeenProgram>>,
 }
@@ -44,16 +49,37 @@ fn exit(app: &App, model: Model) {
     model.vision_handle.join().unwrap();
 }
 
+/// Keeps the database in memory unless PROGSPACE_DATA_DIR names a directory to save it in.
+/// Identical facts are only stored once if PROGSPACE_DEDUPLICATE is set.
+fn open_database() -> Database {
+    let mut db = match std::env::var("PROGSPACE_DATA_DIR") {
+        Ok(directory) => {
+            let config = persistence::PersistenceConfig::new(directory.clone().into());
+            match Database::with_persistence(config) {
+                Ok(db) => db,
+                Err(e) => panic!("could not open the database in {}: {}", directory, e),
+            }
+        }
+        Err(_) => Database::new(),
+    };
+    if std::env::var_os("PROGSPACE_DEDUPLICATE").is_some() {
+        db.set_deduplicate(true);
+    }
+    db
+}
+
 fn model(_app: &App) -> Model {
     lazy_static! {
-        static ref static_db: Mutex<Database> = Mutex::new(Database::new());
+        static ref static_db: Mutex<Database> = Mutex::new(open_database());
     }
 
-    let mut source_code_manager = source_code::SourceCodeManager::new("../../scripts/".to_string());
+    let mut source_code_manager = source_code::SourceCodeManager::new("./scripts".to_string());
     // source_code_manager.init(&mut db);
     source_code_manager.init(&static_db);
     let start = Instant::now();
-    source_code_manager.update(&static_db);
+    if let Err(e) = source_code_manager.update(&static_db) {
+        println!("{}", e);
+    }
     let duration = start.elapsed();
     println!("Time elapsed in expensive_function() is: {:?}", duration);
     static_db.lock().unwrap().print();
@@ -66,12 +92,25 @@ fn model(_app: &App) -> Model {
     Model {
         vision_handle: vision::run_vision(&shared_frame, tx),
         static_db: &static_db,
+        source_code_manager,
         main_frame: main_frame,
         rx: rx,
     }
 }
 
 fn update(_app: &App, _model: &mut Model, _update: Update) {
+    _model.static_db.lock().unwrap().advance_tick();
+    if _app.elapsed_frames() % 30 == 0 {
+        _model
+            .source_code_manager
+            .reload_changed_scripts(_model.static_db);
+    }
+    _model
+        .source_code_manager
+        .update_running_programs(_model.static_db);
+    if let Err(e) = _model.source_code_manager.update(_model.static_db) {
+        println!("{}", e);
+    }
     if _app.elapsed_frames() % 10 == 0 {
         println!("FPS: {}", _app.fps());
     }
@@ -84,8 +123,12 @@ fn view(_app: &App, _model: &Model, _frame: Frame) {
 
     let generic_graphics_wishes = db.select(&vec!["$ wish $ had graphics $graphics".to_string()]);
     for wish in generic_graphics_wishes.iter() {
-        if let Term::Text(g) = &wish.result.first().unwrap().term {
-            let j: serde_json::Value = serde_json::from_str(&g).unwrap();
+        let graphics = match &wish.result.first().unwrap().term {
+            Term::Text(g) => serde_json::from_str(&g).ok(),
+            Term::Json(j) => Some(j.clone()),
+            _ => None,
+        };
+        if let Some(j) = graphics {
             let draw = nannou::Draw::new();
             draw.reset();
             draw.background().color(BLACK);
@@ -137,23 +180,19 @@ fn view(_app: &App, _model: &Model, _frame: Frame) {
     // TODO: use a single_value_channel
     if let Ok(seen_programs) = _model.rx.recv() {
         // println!("---{:?}", seen_programs);
-        db.retract("#0cv %");
+        db.retract("#0cv %").unwrap();
         for p in seen_programs.iter() {
-            db.claim(Fact::from_string(
-                format!(
-                    "#0cv program {} at {} {} {} {} {} {} {} {}",
-                    p.id,
-                    p.corner1.x,
-                    p.corner1.y,
-                    p.corner2.x,
-                    p.corner2.y,
-                    p.corner3.x,
-                    p.corner3.y,
-                    p.corner4.x,
-                    p.corner4.y
-                )
-                .as_str(),
-            ));
+            let mut terms = vec![
+                Term::Id("0cv".to_string()),
+                Term::Text("program".to_string()),
+                Term::Integer(p.id.into()),
+                Term::Text("at".to_string()),
+            ];
+            for corner in [p.corner1, p.corner2, p.corner3, p.corner4].iter() {
+                terms.push(Term::Float(corner.x.into()));
+                terms.push(Term::Float(corner.y.into()));
+            }
+            db.claim_from(FactSource::Vision, Fact::from_terms(&terms[..]));
         }
 
         // let frame = _model.main_frame.lock().unwrap();
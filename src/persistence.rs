@@ -0,0 +1,300 @@
+use crate::fact::{Fact, Term};
+use crate::index::FactId;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+const SNAPSHOT_FILE: &str = "snapshot.jsonl";
+const LOG_FILE: &str = "log.jsonl";
+
+#[derive(Clone, Debug)]
+pub struct PersistenceConfig {
+    pub directory: PathBuf,
+    /// Facts whose first term is one of these ids are kept in memory only. Defaults to
+    /// the camera (`#0cv`) and the source code facts (`#00`), which are claimed again on
+    /// every boot anyway.
+    pub excluded_owners: Vec<String>,
+    /// How many log entries to write before compacting the log into a snapshot.
+    pub snapshot_interval: usize,
+}
+impl PersistenceConfig {
+    pub fn new(directory: PathBuf) -> Self {
+        PersistenceConfig {
+            directory,
+            excluded_owners: vec!["0cv".to_string(), "00".to_string()],
+            snapshot_interval: 1000,
+        }
+    }
+
+    pub fn persists(&self, fact: &Fact) -> bool {
+        match fact.owner() {
+            Some(owner) => !self
+                .excluded_owners
+                .iter()
+                .any(|excluded| excluded == owner),
+            None => true,
+        }
+    }
+}
+
+/// Terms are stored as `[type, value]` pairs so they come back with the same type.
+fn term_to_record(term: &Term) -> Value {
+    match term {
+        Term::Text(text) => json!(["text", text]),
+        Term::Id(text) => json!(["id", text]),
+        Term::Variable(text) => json!(["variable", text]),
+        Term::Postfix(text) => json!(["postfix", text]),
+        Term::Integer(i) => json!(["integer", i]),
+        Term::Float(f) => json!(["float", f]),
+        Term::Boolean(b) => json!(["boolean", b]),
+        Term::Json(value) => json!(["json", value]),
+        Term::Blob(bytes) => json!(["blob", bytes]),
+    }
+}
+
+fn term_from_record(record: &Value) -> Option<Term> {
+    let value = record.get(1)?;
+    let term = match record.get(0)?.as_str()? {
+        "text" => Term::Text(value.as_str()?.to_string()),
+        "id" => Term::Id(value.as_str()?.to_string()),
+        "variable" => Term::Variable(value.as_str()?.to_string()),
+        "postfix" => Term::Postfix(value.as_str()?.to_string()),
+        "integer" => Term::Integer(value.as_i64()?),
+        "float" => Term::Float(value.as_f64()?),
+        "boolean" => Term::Boolean(value.as_bool()?),
+        "json" => Term::Json(value.clone()),
+        "blob" => Term::Blob(
+            value
+                .as_array()?
+                .iter()
+                .map(|b| b.as_u64().map(|b| b as u8))
+                .collect::<Option<Vec<u8>>>()?,
+        ),
+        _ => return None,
+    };
+    Some(term)
+}
+
+fn fact_to_record(fact: &Fact) -> Value {
+    Value::Array(fact.terms.iter().map(term_to_record).collect())
+}
+
+fn fact_from_record(record: &Value) -> Option<Fact> {
+    let terms = record
+        .as_array()?
+        .iter()
+        .map(term_from_record)
+        .collect::<Option<Vec<Term>>>()?;
+    Some(Fact::from_terms(&terms))
+}
+
+/// Reads one JSON record per line. A line that can't be read ends the file: it is most
+/// likely a write that was cut short by a crash.
+fn read_records(path: &PathBuf) -> io::Result<Vec<Value>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut records: Vec<Value> = vec![];
+    for (line_number, line) in BufReader::new(file).lines().enumerate() {
+        match serde_json::from_str::<Value>(&line?) {
+            Ok(record) => records.push(record),
+            Err(e) => {
+                println!(
+                    "ignoring the rest of {} from line {}: {}",
+                    path.display(),
+                    line_number + 1,
+                    e
+                );
+                break;
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// An append-only log of claims and retracts plus a snapshot the log gets compacted into.
+/// Every entry is written to the OS right after the change is made in memory, so the log
+/// survives the process crashing; the snapshot is also synced to disk.
+pub struct Persistence {
+    config: PersistenceConfig,
+    log: File,
+    entries_since_snapshot: usize,
+}
+impl Persistence {
+    /// Opens the files in `config.directory`, creating it if needed, and returns the facts
+    /// saved there by the snapshot and the log after it.
+    pub fn open(config: PersistenceConfig) -> io::Result<(Persistence, BTreeMap<FactId, Fact>)> {
+        fs::create_dir_all(&config.directory)?;
+        let mut facts: BTreeMap<FactId, Fact> = BTreeMap::new();
+        for record in read_records(&config.directory.join(SNAPSHOT_FILE))? {
+            if let (Some(id), Some(fact)) =
+                (record["id"].as_u64(), fact_from_record(&record["terms"]))
+            {
+                facts.insert(id, fact);
+            }
+        }
+        for record in read_records(&config.directory.join(LOG_FILE))? {
+            if let Some(id) = record["retract"].as_u64() {
+                facts.remove(&id);
+            } else if let (Some(id), Some(fact)) =
+                (record["claim"].as_u64(), fact_from_record(&record["terms"]))
+            {
+                facts.insert(id, fact);
+            }
+        }
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(config.directory.join(LOG_FILE))?;
+        let mut persistence = Persistence {
+            config,
+            log,
+            entries_since_snapshot: 0,
+        };
+        // Start from a fresh snapshot and an empty log, which also drops a torn last line
+        // that new entries would otherwise be appended to
+        persistence.write_snapshot(facts.iter())?;
+        Ok((persistence, facts))
+    }
+
+    pub fn config(&self) -> &PersistenceConfig {
+        &self.config
+    }
+
+    fn append(&mut self, record: Value) -> io::Result<()> {
+        self.log.write_all(format!("{}\n", record).as_bytes())?;
+        self.entries_since_snapshot += 1;
+        Ok(())
+    }
+
+    pub fn log_claim(&mut self, id: FactId, fact: &Fact) -> io::Result<()> {
+        self.append(json!({"claim": id, "terms": fact_to_record(fact)}))
+    }
+
+    pub fn log_retract(&mut self, id: FactId) -> io::Result<()> {
+        self.append(json!({ "retract": id }))
+    }
+
+    pub fn needs_snapshot(&self) -> bool {
+        self.entries_since_snapshot >= self.config.snapshot_interval
+    }
+
+    /// Replaces the snapshot with `facts` and empties the log. The new snapshot is written
+    /// next to the old one and renamed over it, so a crash leaves one or the other intact.
+    pub fn write_snapshot<'a>(
+        &mut self,
+        facts: impl Iterator<Item = (&'a FactId, &'a Fact)>,
+    ) -> io::Result<()> {
+        let snapshot_path = self.config.directory.join(SNAPSHOT_FILE);
+        let temporary_path = snapshot_path.with_extension("jsonl.tmp");
+        let mut snapshot = File::create(&temporary_path)?;
+        for (id, fact) in facts {
+            let record = json!({"id": id, "terms": fact_to_record(fact)});
+            snapshot.write_all(format!("{}\n", record).as_bytes())?;
+        }
+        snapshot.sync_all()?;
+        fs::rename(&temporary_path, &snapshot_path)?;
+        self.log.set_len(0)?;
+        self.log.sync_all()?;
+        self.entries_since_snapshot = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::Database;
+
+    fn test_directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("progspace-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn all_facts(db: &Database) -> Vec<String> {
+        db.select(&vec!["%fact".to_string()])
+            .into_iter()
+            .map(|r| r.result[0].term.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn restore_from_log_and_snapshot() {
+        let directory = test_directory("persistence");
+        let mut config = PersistenceConfig::new(directory.clone());
+        config.snapshot_interval = 4;
+
+        let mut db = Database::with_persistence(config.clone()).unwrap();
+        db.claim(Fact::from_string("#1 fox is red"));
+        db.claim(Fact::from_string("#0cv program 3 at 1 2 3 4 5 6 7 8"));
+        db.claim(Fact::from_terms(&[
+            Term::Id("2".to_string()),
+            Term::Json(json!({"x": 1})),
+            Term::Float(1.0),
+            Term::Blob(vec![0, 255]),
+        ]));
+        db.claim_for_subscription(0, Fact::from_string("#4 you see a fox"));
+        db.retract("#1 %").unwrap();
+        db.claim(Fact::from_string("#1 fox is \"very red\""));
+        let expected: Vec<String> = all_facts(&db)
+            .into_iter()
+            .filter(|f| !f.starts_with("#0cv") && !f.starts_with("#4"))
+            .collect();
+        assert_eq!(expected.len(), 2);
+        drop(db);
+
+        let restored = Database::with_persistence(config.clone()).unwrap();
+        assert_eq!(all_facts(&restored), expected);
+        let typed = restored.select(&vec!["#2 $j $f $b".to_string()]);
+        assert!(matches!(typed[0].result[0].term, Term::Json(_)));
+        assert_eq!(typed[0].result[1].term, Term::Float(1.0));
+        assert_eq!(typed[0].result[2].term, Term::Blob(vec![0, 255]));
+        drop(restored);
+
+        // A write cut short by a crash loses only that entry
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(directory.join(LOG_FILE))
+            .unwrap();
+        log.write_all(b"{\"claim\": 99, \"ter").unwrap();
+        drop(log);
+        let mut restored = Database::with_persistence(config.clone()).unwrap();
+        assert_eq!(all_facts(&restored), expected);
+        restored.claim(Fact::from_string("#1 fox is blue"));
+        drop(restored);
+        let restored = Database::with_persistence(config).unwrap();
+        assert_eq!(all_facts(&restored).len(), 3);
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn restarts_do_not_pile_up_claims() {
+        let directory = test_directory("restarts");
+        let config = PersistenceConfig::new(directory.clone());
+        for _ in 0..5 {
+            let mut db = Database::with_persistence(config.clone()).unwrap();
+            // What the boot program claims every time it starts
+            db.claim(Fact::from_string("#0 program 3 at 0 0 1 0 1 1 0 1"));
+            assert_eq!(all_facts(&db), vec!["#0 program 3 at 0 0 1 0 1 1 0 1"]);
+        }
+
+        // Claims beyond the restored ones are still stored, and a restored fact that was
+        // retracted isn't brought back
+        let mut db = Database::with_persistence(config.clone()).unwrap();
+        db.claim(Fact::from_string("#0 program 3 at 0 0 1 0 1 1 0 1"));
+        db.claim(Fact::from_string("#0 program 3 at 0 0 1 0 1 1 0 1"));
+        assert_eq!(all_facts(&db).len(), 2);
+        drop(db);
+        let mut db = Database::with_persistence(config).unwrap();
+        db.retract("#0 %").unwrap();
+        db.claim(Fact::from_string("#0 program 3 at 0 0 1 0 1 1 0 1"));
+        assert_eq!(all_facts(&db).len(), 1);
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
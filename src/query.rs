@@ -0,0 +1,481 @@
+use crate::database::QueryResult;
+use crate::fact::{Fact, FactParseError, Term};
+use regex::Regex;
+use std::cmp::Ordering;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryError {
+    /// Index of the query part that could not be used.
+    pub clause_index: usize,
+    pub message: String,
+}
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "query part {}: {}", self.clause_index + 1, self.message)
+    }
+}
+impl std::error::Error for QueryError {}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    StartsWith,
+}
+impl Comparison {
+    fn from_operator(operator: &str) -> Option<Comparison> {
+        match operator {
+            "=" => Some(Comparison::Equal),
+            "!=" => Some(Comparison::NotEqual),
+            "<" => Some(Comparison::Less),
+            "<=" => Some(Comparison::LessOrEqual),
+            ">" => Some(Comparison::Greater),
+            ">=" => Some(Comparison::GreaterOrEqual),
+            "starts_with" => Some(Comparison::StartsWith),
+            _ => None,
+        }
+    }
+
+    fn operator(&self) -> &'static str {
+        match self {
+            Comparison::Equal => "=",
+            Comparison::NotEqual => "!=",
+            Comparison::Less => "<",
+            Comparison::LessOrEqual => "<=",
+            Comparison::Greater => ">",
+            Comparison::GreaterOrEqual => ">=",
+            Comparison::StartsWith => "starts_with",
+        }
+    }
+}
+
+/// Orders two numbers by value or two texts (or ids) alphabetically. Anything else is
+/// unordered, so comparisons between them fail.
+fn compare_terms(a: &Term, b: &Term) -> Option<Ordering> {
+    match (a, b) {
+        (Term::Text(a), Term::Text(b)) | (Term::Id(a), Term::Id(b)) => Some(a.cmp(b)),
+        _ => a.as_f64()?.partial_cmp(&b.as_f64()?),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AggregateFunction {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Collect,
+}
+impl AggregateFunction {
+    fn from_name(name: &str) -> Option<AggregateFunction> {
+        match name {
+            "count" => Some(AggregateFunction::Count),
+            "sum" => Some(AggregateFunction::Sum),
+            "min" => Some(AggregateFunction::Min),
+            "max" => Some(AggregateFunction::Max),
+            "collect" => Some(AggregateFunction::Collect),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            AggregateFunction::Count => "count",
+            AggregateFunction::Sum => "sum",
+            AggregateFunction::Min => "min",
+            AggregateFunction::Max => "max",
+            AggregateFunction::Collect => "collect",
+        }
+    }
+
+    /// Combines the values a variable took within one group. `None` when there is
+    /// nothing to report, like the minimum of no values.
+    pub fn apply(&self, values: &[Term]) -> Option<Term> {
+        match self {
+            AggregateFunction::Count => Some(Term::Integer(values.len() as i64)),
+            AggregateFunction::Sum => {
+                // Stays an integer as long as every value is one; other terms are skipped
+                let mut integer_sum: Option<i64> = Some(0);
+                let mut float_sum: f64 = 0.0;
+                for value in values {
+                    integer_sum = match value {
+                        Term::Integer(i) => integer_sum.and_then(|sum| sum.checked_add(*i)),
+                        Term::Float(_) => None,
+                        _ => continue,
+                    };
+                    float_sum += value.as_f64().unwrap_or(0.0);
+                }
+                Some(integer_sum.map_or(Term::Float(float_sum), Term::Integer))
+            }
+            AggregateFunction::Min | AggregateFunction::Max => {
+                let wanted = match self {
+                    AggregateFunction::Min => Ordering::Less,
+                    _ => Ordering::Greater,
+                };
+                let mut best: Option<&Term> = None;
+                for value in values {
+                    match best {
+                        Some(b) if compare_terms(value, b) != Some(wanted) => {}
+                        _ => best = Some(value),
+                    }
+                }
+                best.cloned()
+            }
+            AggregateFunction::Collect => Some(Term::Json(serde_json::Value::Array(
+                values.iter().map(term_to_json).collect(),
+            ))),
+        }
+    }
+}
+
+fn term_to_json(term: &Term) -> serde_json::Value {
+    match term {
+        Term::Integer(i) => serde_json::Value::from(*i),
+        Term::Float(f) => serde_json::Value::from(*f),
+        Term::Boolean(b) => serde_json::Value::Bool(*b),
+        Term::Json(value) => value.clone(),
+        _ => serde_json::Value::String(term.to_string()),
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Clause {
+    /// Matches facts, binding the pattern's variables.
+    Pattern(Fact),
+    /// Written `not <pattern>`. Succeeds only if no fact matches the pattern given the
+    /// bindings so far. Variables that no other clause binds match anything.
+    Not(Fact),
+    /// Written like `$x < 300`, `$name != fox` or `$url starts_with http`.
+    Compare(Term, Comparison, Term),
+    /// Written `$x between 10 300`; both ends are included.
+    Between(Term, Term, Term),
+    /// Written `$name matches "^fo+x$"`.
+    Matches(Term, Regex),
+    /// Written like `$n = count $id` or `$left = min $x`. Results are grouped by the
+    /// other variables the patterns bind and each group becomes one result with `output`
+    /// bound in place of `input`.
+    Aggregate {
+        output: String,
+        function: AggregateFunction,
+        input: String,
+    },
+}
+impl Clause {
+    fn parse(part: &str) -> Result<Clause, String> {
+        let fact = Fact::parse(part).map_err(|e: FactParseError| e.to_string())?;
+        // Only a bare `not` counts, so a fact that really starts with "not" can be
+        // matched by quoting it
+        let trimmed = part.trim_start();
+        let is_negated = trimmed.starts_with("not")
+            && trimmed[3..].starts_with(char::is_whitespace)
+            && fact.terms.len() > 1;
+        if is_negated {
+            return Ok(Clause::Not(Fact::from_terms(&fact.terms[1..])));
+        }
+        let is_variable = |term: &Term| matches!(term, Term::Variable(name) if !name.is_empty());
+        match &fact.terms[..] {
+            [Term::Variable(output), Term::Text(operator), Term::Text(function), Term::Variable(input)]
+                if operator == "="
+                    && !output.is_empty()
+                    && !input.is_empty()
+                    && AggregateFunction::from_name(function).is_some() =>
+            {
+                return Ok(Clause::Aggregate {
+                    output: output.to_owned(),
+                    function: AggregateFunction::from_name(function).unwrap(),
+                    input: input.to_owned(),
+                });
+            }
+            [left, Term::Text(operator), right] if is_variable(left) || is_variable(right) => {
+                if let Some(comparison) = Comparison::from_operator(operator) {
+                    return Ok(Clause::Compare(left.clone(), comparison, right.clone()));
+                }
+                if operator == "matches" {
+                    // The pattern has to be written out, it can't come from a variable
+                    let pattern = match right {
+                        Term::Text(pattern) if is_variable(left) => pattern,
+                        _ => {
+                            return Err(format!(
+                                "matches needs a variable on the left and a pattern on the right, got {}",
+                                right.to_string()
+                            ))
+                        }
+                    };
+                    let regex = Regex::new(pattern).map_err(|e| e.to_string())?;
+                    return Ok(Clause::Matches(left.clone(), regex));
+                }
+            }
+            [value, Term::Text(operator), low, high]
+                if operator == "between" && is_variable(value) =>
+            {
+                return Ok(Clause::Between(value.clone(), low.clone(), high.clone()));
+            }
+            _ => {}
+        }
+        Ok(Clause::Pattern(fact))
+    }
+
+    /// Filters only narrow down results and never bind variables.
+    pub fn is_filter(&self) -> bool {
+        !matches!(self, Clause::Pattern(_) | Clause::Aggregate { .. })
+    }
+
+    pub fn variables(&self) -> Vec<String> {
+        match self {
+            Clause::Pattern(fact) | Clause::Not(fact) => pattern_variables(fact),
+            Clause::Compare(left, _, right) => {
+                pattern_variables(&Fact::from_terms(&[left.clone(), right.clone()]))
+            }
+            Clause::Between(value, low, high) => pattern_variables(&Fact::from_terms(&[
+                value.clone(),
+                low.clone(),
+                high.clone(),
+            ])),
+            Clause::Matches(value, _) => {
+                pattern_variables(&Fact::from_terms(std::slice::from_ref(value)))
+            }
+            Clause::Aggregate { input, .. } => vec![input.to_owned()],
+        }
+    }
+
+    /// Whether a comparison, range or regex clause holds for the bindings in `env`.
+    /// Patterns and `not` clauses need the database and are evaluated there instead.
+    pub fn passes(&self, env: &QueryResult) -> bool {
+        let resolve = |term: &Term| -> Option<Term> {
+            match term {
+                Term::Variable(name) => env
+                    .result
+                    .iter()
+                    .find(|r| r.variable_name.eq(name))
+                    .map(|r| r.term.clone()),
+                _ => Some(term.clone()),
+            }
+        };
+        match self {
+            Clause::Pattern(_) | Clause::Not(_) | Clause::Aggregate { .. } => true,
+            Clause::Compare(left, comparison, right) => {
+                let (left, right) = match (resolve(left), resolve(right)) {
+                    (Some(left), Some(right)) => (left, right),
+                    _ => return false,
+                };
+                let ordering = compare_terms(&left, &right);
+                match comparison {
+                    Comparison::Equal => left == right,
+                    Comparison::NotEqual => left != right,
+                    Comparison::Less => ordering == Some(Ordering::Less),
+                    Comparison::LessOrEqual => {
+                        matches!(ordering, Some(Ordering::Less) | Some(Ordering::Equal))
+                    }
+                    Comparison::Greater => ordering == Some(Ordering::Greater),
+                    Comparison::GreaterOrEqual => {
+                        matches!(ordering, Some(Ordering::Greater) | Some(Ordering::Equal))
+                    }
+                    Comparison::StartsWith => left.to_string().starts_with(&right.to_string()),
+                }
+            }
+            Clause::Between(value, low, high) => {
+                match (resolve(value), resolve(low), resolve(high)) {
+                    (Some(value), Some(low), Some(high)) => {
+                        matches!(
+                            compare_terms(&low, &value),
+                            Some(Ordering::Less) | Some(Ordering::Equal)
+                        ) && matches!(
+                            compare_terms(&value, &high),
+                            Some(Ordering::Less) | Some(Ordering::Equal)
+                        )
+                    }
+                    _ => false,
+                }
+            }
+            Clause::Matches(value, regex) => match resolve(value) {
+                Some(value) => regex.is_match(&value.to_string()),
+                None => false,
+            },
+        }
+    }
+
+    pub fn to_string(&self) -> String {
+        match self {
+            Clause::Pattern(fact) => fact.to_string(),
+            Clause::Not(fact) => format!("not {}", fact.to_string()),
+            Clause::Compare(left, comparison, right) => format!(
+                "{} {} {}",
+                left.to_fact_string(),
+                comparison.operator(),
+                right.to_fact_string()
+            ),
+            Clause::Between(value, low, high) => format!(
+                "{} between {} {}",
+                value.to_fact_string(),
+                low.to_fact_string(),
+                high.to_fact_string()
+            ),
+            Clause::Matches(value, regex) => format!(
+                "{} matches {}",
+                value.to_fact_string(),
+                Term::Text(regex.as_str().to_string()).to_fact_string()
+            ),
+            Clause::Aggregate {
+                output,
+                function,
+                input,
+            } => format!("${} = {} ${}", output, function.name(), input),
+        }
+    }
+}
+
+/// The parsed form of the query parts given to `Database::select` or `when`.
+#[derive(Clone, Debug)]
+pub struct Query {
+    pub clauses: Vec<Clause>,
+}
+impl Query {
+    pub fn parse(query_parts: &[String]) -> Result<Query, QueryError> {
+        let mut clauses: Vec<Clause> = vec![];
+        for (clause_index, part) in query_parts.iter().enumerate() {
+            let clause = Clause::parse(part).map_err(|message| QueryError {
+                clause_index,
+                message,
+            })?;
+            if let Clause::Pattern(fact) = &clause {
+                if fact.terms.is_empty() {
+                    return Err(QueryError {
+                        clause_index,
+                        message: "empty query part".to_string(),
+                    });
+                }
+            }
+            clauses.push(clause);
+        }
+        let query = Query { clauses };
+        let bound_by_patterns = query.variable_names();
+        let mut outputs: Vec<&String> = vec![];
+        for (clause_index, clause) in query.clauses.iter().enumerate() {
+            if let Clause::Aggregate { output, .. } = clause {
+                if bound_by_patterns.contains(output) || outputs.contains(&output) {
+                    return Err(QueryError {
+                        clause_index,
+                        message: format!("${} is already bound", output),
+                    });
+                }
+                outputs.push(output);
+            }
+            if matches!(clause, Clause::Pattern(_) | Clause::Not(_)) {
+                continue;
+            }
+            if let Some(name) = clause
+                .variables()
+                .into_iter()
+                .find(|name| !bound_by_patterns.contains(name))
+            {
+                return Err(QueryError {
+                    clause_index,
+                    message: format!("${} is not bound by any pattern", name),
+                });
+            }
+        }
+        Ok(query)
+    }
+
+    /// Named variables bound by the query's patterns in the order they first appear, which
+    /// is the order bindings are reported in no matter how the clauses get evaluated.
+    pub fn variable_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = vec![];
+        for clause in self
+            .clauses
+            .iter()
+            .filter(|c| matches!(c, Clause::Pattern(_)))
+        {
+            for name in clause.variables() {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        names
+    }
+
+    pub fn has_aggregates(&self) -> bool {
+        self.clauses
+            .iter()
+            .any(|c| matches!(c, Clause::Aggregate { .. }))
+    }
+
+    /// The variables results are grouped by when the query has aggregates: everything the
+    /// patterns bind except the aggregated variables.
+    pub fn group_variable_names(&self) -> Vec<String> {
+        let inputs: Vec<&String> = self
+            .clauses
+            .iter()
+            .filter_map(|c| match c {
+                Clause::Aggregate { input, .. } => Some(input),
+                _ => None,
+            })
+            .collect();
+        self.variable_names()
+            .into_iter()
+            .filter(|name| !inputs.contains(&name))
+            .collect()
+    }
+
+    /// The variables that must be bound before the filter at `clause_index` can run.
+    pub fn filter_inputs(&self, clause_index: usize) -> Vec<String> {
+        let bound_by_patterns = self.variable_names();
+        self.clauses[clause_index]
+            .variables()
+            .into_iter()
+            .filter(|name| bound_by_patterns.contains(name))
+            .collect()
+    }
+}
+
+pub fn pattern_variables(pattern: &Fact) -> Vec<String> {
+    pattern
+        .terms
+        .iter()
+        .filter_map(|term| match term {
+            Term::Variable(name) | Term::Postfix(name) if !name.is_empty() => Some(name.to_owned()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlanStep {
+    /// Index of the clause in the query as written.
+    pub clause_index: usize,
+    pub clause: String,
+    /// Variables already bound by earlier steps when this clause is evaluated.
+    pub bound_variables: Vec<String>,
+    pub estimated_rows: usize,
+}
+
+/// The order `Database::select` will evaluate a query's clauses in, cheapest first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryPlan {
+    pub steps: Vec<PlanStep>,
+}
+impl fmt::Display for QueryPlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, step) in self.steps.iter().enumerate() {
+            write!(
+                f,
+                "{}. {} (part {}, ~{} rows",
+                i + 1,
+                step.clause,
+                step.clause_index + 1,
+                step.estimated_rows
+            )?;
+            if !step.bound_variables.is_empty() {
+                write!(f, ", given ${}", step.bound_variables.join(" $"))?;
+            }
+            writeln!(f, ")")?;
+        }
+        Ok(())
+    }
+}
@@ -1,14 +1,19 @@
-use crate::database::{Database, Subscription};
+use crate::database::{Database, Expiry, QueryResult, ResultChanges, Subscription, SubscriptionId};
 use crate::fact::{Fact, Term};
 use crate::illumination::Illumination;
+use crate::query::Query;
 
 use lazy_static::lazy_static;
+use mlua::HookTriggers;
 use mlua::{prelude::*, Function, Lua, RegistryKey, Table, Variadic, Value, Result, Error as LuaError};
 use regex::Regex;
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs;
+use std::rc::Rc;
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 // enum ProgramUpdate {
 //     Claim(String),
@@ -16,128 +21,927 @@ use std::sync::Mutex;
 //     Cleanup(),
 // }
 
+fn conversion_error(from: &'static str, message: String) -> LuaError {
+    LuaError::FromLuaConversionError {
+        from,
+        to: "Fact",
+        message: Some(message),
+    }
+}
+
+/// The values of `t` in order if its keys are exactly 1..n, otherwise `None`.
+fn lua_sequence<'lua>(t: &Table<'lua>) -> Result<Option<Vec<Value<'lua>>>> {
+    let mut entries: Vec<(i64, Value)> = vec![];
+    for pair in t.clone().pairs::<Value, Value>() {
+        match pair? {
+            (Value::Integer(i), v) if i >= 1 => entries.push((i, v)),
+            _ => return Ok(None),
+        }
+    }
+    entries.sort_by_key(|(i, _)| *i);
+    if entries
+        .iter()
+        .enumerate()
+        .any(|(n, (i, _))| *i != n as i64 + 1)
+    {
+        return Ok(None);
+    }
+    Ok(Some(entries.into_iter().map(|(_, v)| v).collect()))
+}
+
+fn lua_to_json(value: Value, depth: usize) -> Result<serde_json::Value> {
+    if depth > 32 {
+        return Err(conversion_error(
+            "table",
+            "table is nested too deeply (or recursive)".to_string(),
+        ));
+    }
+    Ok(match value {
+        Value::Nil => serde_json::Value::Null,
+        Value::Boolean(b) => serde_json::Value::Bool(b),
+        Value::Integer(i) => serde_json::Value::from(i),
+        Value::Number(n) => match serde_json::Number::from_f64(n) {
+            Some(n) => serde_json::Value::Number(n),
+            None => {
+                return Err(conversion_error(
+                    "number",
+                    format!("{} cannot be stored", n),
+                ))
+            }
+        },
+        Value::String(s) => serde_json::Value::String(s.to_str()?.to_string()),
+        Value::Table(t) => match lua_sequence(&t)? {
+            Some(items) => serde_json::Value::Array(
+                items
+                    .into_iter()
+                    .map(|item| lua_to_json(item, depth + 1))
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+            None => {
+                let mut fields = serde_json::Map::new();
+                for pair in t.pairs::<Value, Value>() {
+                    let (k, v) = pair?;
+                    let key = match k {
+                        Value::String(s) => s.to_str()?.to_string(),
+                        Value::Integer(i) => i.to_string(),
+                        Value::Number(n) => n.to_string(),
+                        other => {
+                            return Err(conversion_error(
+                                other.type_name(),
+                                "table keys must be strings or numbers".to_string(),
+                            ))
+                        }
+                    };
+                    fields.insert(key, lua_to_json(v, depth + 1)?);
+                }
+                serde_json::Value::Object(fields)
+            }
+        },
+        other => {
+            return Err(conversion_error(
+                other.type_name(),
+                format!("a {} cannot be part of a fact", other.type_name()),
+            ))
+        }
+    })
+}
+
+/// Converts what a program passes to `claim` into terms:
+/// - strings are parsed as facts, so `"fox is red"` is three terms
+/// - numbers and booleans become a single typed term
+/// - `{"", value}` is one text term whose value is taken verbatim, spaces and all
+/// - other sequences like `{"sensor", 7, "reading", 3.14}` are converted element by element
+/// - empty tables and tables with non-sequence keys like `{x=1, y=2}` become a single JSON
+///   term
 impl<'lua> FromLua<'lua> for Fact {
-    #[inline]
-    fn from_lua(lua_value: Value<'lua>, _: &'lua Lua) -> Result<Self> {
-        println!("LUA VALUE TYPE::: {:?}", lua_value.type_name());
+    fn from_lua(lua_value: Value<'lua>, lua: &'lua Lua) -> Result<Self> {
         match lua_value {
-            mlua::Value::String(v) => Ok(Fact::from_string(v.to_str().unwrap())),
-            mlua::Value::Table(t) => {
-                match t.get(1).unwrap() {
-                    mlua::Value::String(fact_type) => {
-                        let fact_type = fact_type.to_str().unwrap();
-                        match fact_type {
-                            "" | "text" => Ok(Fact::from_terms(&vec![Term::Text(t.get(2).unwrap())][..])),
-                            "id" => Ok(Fact::from_terms(&vec![Term::Id(t.get(2).unwrap())][..])),
-                            "variable" => Ok(Fact::from_terms(&vec![Term::Variable(t.get(2).unwrap())][..])),
-                            "postfix" => Ok(Fact::from_terms(&vec![Term::Postfix(t.get(2).unwrap())][..])),
-                            _ => Err(LuaError::RuntimeError("TODO error converting, bad fact from lua, case 3".to_string()))
+            Value::String(v) => Fact::parse(v.to_str()?).map_err(LuaError::external),
+            Value::Integer(i) => Ok(Fact::from_terms(&[Term::Integer(i)])),
+            Value::Number(n) => Ok(Fact::from_terms(&[Term::Float(n)])),
+            Value::Boolean(b) => Ok(Fact::from_terms(&[Term::Boolean(b)])),
+            Value::Table(t) => match lua_sequence(&t)? {
+                Some(items) if !items.is_empty() => {
+                    if let [Value::String(fact_type), value] = &items[..] {
+                        if fact_type.as_bytes().is_empty() {
+                            let text = lua.coerce_string(value.clone())?.ok_or_else(|| {
+                                conversion_error(
+                                    value.type_name(),
+                                    "expected a string value".to_string(),
+                                )
+                            })?;
+                            return Ok(Fact::from_terms(&[Term::Text(text.to_str()?.to_string())]));
                         }
-                        
-                    },
-                    _ => Err(LuaError::RuntimeError("TODO error converting, bad fact from lua, case 2".to_string()))
+                    }
+                    let mut terms: Vec<Term> = vec![];
+                    for item in items {
+                        terms.extend(Fact::from_lua(item, lua)?.terms);
+                    }
+                    Ok(Fact::from_terms(&terms[..]))
                 }
+                _ => Ok(Fact::from_terms(&[Term::Json(lua_to_json(
+                    Value::Table(t),
+                    0,
+                )?)])),
             },
-            _ => Err(LuaError::RuntimeError("TODO error converting, bad fact from lua".to_string()))
+            other => Err(conversion_error(
+                other.type_name(),
+                format!("cannot claim a {}", other.type_name()),
+            )),
+        }
+    }
+}
+
+fn json_to_lua<'lua>(lua: &'lua Lua, value: &serde_json::Value) -> Result<Value<'lua>> {
+    Ok(match value {
+        serde_json::Value::Null => Value::Nil,
+        serde_json::Value::Bool(b) => Value::Boolean(*b),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Number(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        serde_json::Value::String(s) => Value::String(lua.create_string(s)?),
+        serde_json::Value::Array(items) => {
+            let table = lua.create_table()?;
+            for (i, item) in items.iter().enumerate() {
+                table.set(i + 1, json_to_lua(lua, item)?)?;
+            }
+            Value::Table(table)
+        }
+        serde_json::Value::Object(fields) => {
+            let table = lua.create_table()?;
+            for (key, item) in fields.iter() {
+                table.set(key.as_str(), json_to_lua(lua, item)?)?;
+            }
+            Value::Table(table)
+        }
+    })
+}
+
+/// Converts a term into the native Lua value a callback sees in its results.
+fn term_to_lua<'lua>(lua: &'lua Lua, term: &Term) -> Result<Value<'lua>> {
+    Ok(match term {
+        Term::Integer(i) => Value::Integer(*i),
+        Term::Float(f) => Value::Number(*f),
+        Term::Boolean(b) => Value::Boolean(*b),
+        Term::Json(value) => json_to_lua(lua, value)?,
+        Term::Blob(bytes) => Value::String(lua.create_string(bytes)?),
+        _ => Value::String(lua.create_string(&term.to_string())?),
+    })
+}
+
+/// The fact a program claims with `claim(...)`: its own id followed by the given terms.
+fn program_fact(program_id: i32, va: &Variadic<Fact>) -> Fact {
+    let mut fact = Fact {
+        terms: vec![Term::Id(program_id.to_string())],
+    };
+    for f in va.iter() {
+        fact.terms.extend(f.terms.iter().cloned());
+    }
+    fact
+}
+
+/// Returned by `SourceCodeManager::update` when subscriptions keep changing each other's
+/// results and never settle.
+#[derive(Debug)]
+pub struct ConvergenceError {
+    pub iterations: usize,
+    /// Programs whose subscriptions were still firing in the last iteration.
+    pub program_ids: Vec<String>,
+}
+impl fmt::Display for ConvergenceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "subscriptions did not settle after {} iterations, still firing in programs {}",
+            self.iterations,
+            self.program_ids.join(", ")
+        )
+    }
+}
+impl std::error::Error for ConvergenceError {}
+
+/// How much a program can do each time it or one of its callbacks runs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExecutionBudget {
+    pub instructions: u64,
+    pub duration: Duration,
+}
+impl Default for ExecutionBudget {
+    fn default() -> Self {
+        ExecutionBudget {
+            instructions: 10_000_000,
+            duration: Duration::from_millis(100),
         }
     }
 }
 
+/// How often the instruction hook checks the budget of the running program.
+const INSTRUCTIONS_PER_BUDGET_CHECK: u32 = 1000;
+
+#[derive(Clone, Debug)]
+pub enum BudgetExceeded {
+    Instructions(u64),
+    Duration(Duration),
+}
+impl fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BudgetExceeded::Instructions(instructions) => write!(
+                f,
+                "timed out after running more than {} instructions",
+                instructions
+            ),
+            BudgetExceeded::Duration(duration) => {
+                write!(f, "timed out after running longer than {:?}", duration)
+            }
+        }
+    }
+}
+impl std::error::Error for BudgetExceeded {}
+
+#[derive(Clone, Debug)]
+pub struct MemoryLimitExceeded {
+    pub limit: usize,
+}
+impl fmt::Display for MemoryLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ran out of its memory limit of {} bytes", self.limit)
+    }
+}
+impl std::error::Error for MemoryLimitExceeded {}
+
+/// Whether `error` comes from an allocation failing, possibly inside a Rust callback.
+fn is_memory_error(error: &LuaError) -> bool {
+    match error {
+        LuaError::MemoryError(_) => true,
+        LuaError::CallbackError { cause, .. } => is_memory_error(cause),
+        _ => false,
+    }
+}
+
+/// The budget of the program that is running, checked by the instruction hook.
+struct RunningBudget {
+    budget: ExecutionBudget,
+    started: Instant,
+    instructions_run: u64,
+    /// Set the first time the budget runs out, even if the program catches the error.
+    exceeded: Option<BudgetExceeded>,
+}
+impl RunningBudget {
+    fn check(&mut self) -> std::result::Result<(), BudgetExceeded> {
+        if self.exceeded.is_none() {
+            if self.instructions_run > self.budget.instructions {
+                self.exceeded = Some(BudgetExceeded::Instructions(self.budget.instructions));
+            } else if self.started.elapsed() > self.budget.duration {
+                self.exceeded = Some(BudgetExceeded::Duration(self.budget.duration));
+            }
+        }
+        match &self.exceeded {
+            Some(exceeded) => Err(exceeded.clone()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Globals every program gets. Anything that reaches outside the Lua state, like `io`,
+/// `os.execute` or `require`, needs a capability.
+const SANDBOX_GLOBALS: &[&str] = &[
+    "assert",
+    "error",
+    "getmetatable",
+    "ipairs",
+    "next",
+    "pairs",
+    "pcall",
+    "print",
+    "rawequal",
+    "rawget",
+    "rawlen",
+    "rawset",
+    "select",
+    "tonumber",
+    "tostring",
+    "type",
+    "xpcall",
+    "_VERSION",
+];
+/// Libraries every program gets its own copy of, so changing one doesn't affect others.
+const SANDBOX_LIBRARIES: &[&str] = &["coroutine", "math", "string", "table", "utf8"];
+const SANDBOX_OS_FUNCTIONS: &[&str] = &["clock", "date", "difftime", "time"];
+/// Modules a program with the network capability can `require`: LuaSocket and LuaSec.
+const NETWORK_MODULES: &[&str] = &["socket", "ssl", "mime", "ltn12"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Capability {
+    Filesystem,
+    Process,
+    Network,
+}
+impl Capability {
+    pub fn from_name(name: &str) -> Option<Capability> {
+        match name {
+            "filesystem" => Some(Capability::Filesystem),
+            "process" => Some(Capability::Process),
+            "network" => Some(Capability::Network),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::Filesystem => "filesystem",
+            Capability::Process => "process",
+            Capability::Network => "network",
+        }
+    }
+
+    /// The `os` functions a program gets with this capability, on top of the sandbox's.
+    fn os_functions(&self) -> &'static [&'static str] {
+        match self {
+            Capability::Filesystem => &["remove", "rename", "tmpname"],
+            Capability::Process => &["execute", "exit", "getenv"],
+            Capability::Network => &[],
+        }
+    }
+
+    /// The `io` functions a program gets with this capability. `io.popen` runs a shell
+    /// command, so it comes with the process capability rather than the filesystem one.
+    fn io_functions(&self) -> &'static [&'static str] {
+        match self {
+            Capability::Filesystem => &[
+                "close", "input", "lines", "open", "output", "read", "stderr", "stdin", "stdout",
+                "tmpfile", "type", "write",
+            ],
+            Capability::Process => &["popen"],
+            Capability::Network => &[],
+        }
+    }
+}
+
+/// A shallow copy of `table`, so a program changing it doesn't change it for the others.
+fn copy_table<'lua>(lua: &'lua Lua, table: &Table<'lua>) -> Result<Table<'lua>> {
+    let copy = lua.create_table()?;
+    for pair in table.clone().pairs::<Value, Value>() {
+        let (key, value) = pair?;
+        copy.set(key, value)?;
+    }
+    Ok(copy)
+}
+
+/// Whether a program with the network capability may `require` `module`.
+fn is_network_module(module: &str) -> bool {
+    NETWORK_MODULES
+        .iter()
+        .any(|name| module == *name || module.starts_with(&format!("{}.", name)))
+}
+
 pub struct SourceCodeManager {
     source_code_folder_path: String,
     // subscriptions: Vec<Subscription>,
     lua_state: Lua,
+    /// The table each running program's globals and API functions live in, built from the
+    /// sandbox and the program's capabilities.
+    program_environments: HashMap<i32, RegistryKey>,
     script_paths: HashMap<i32, String>,
     script_source_codes: HashMap<i32, String>,
+    max_update_iterations: usize,
+    /// Programs that have been run and not stopped since.
+    running_programs: HashSet<i32>,
+    /// Programs that run from startup whether they are seen or not.
+    boot_programs: Vec<i32>,
+    program_last_seen: HashMap<i32, Instant>,
+    stop_grace_period: Duration,
+    /// Programs that raised an error, which stay stopped until their script changes.
+    quarantined_programs: HashSet<i32>,
+    /// Capabilities granted by configuration, on top of those granted by facts.
+    capabilities: HashMap<i32, HashSet<Capability>>,
+    /// Programs allowed to use `retract_any`, like the boot program.
+    privileged_programs: Vec<i32>,
+    default_execution_budget: ExecutionBudget,
+    execution_budgets: HashMap<i32, ExecutionBudget>,
+    running_budget: Rc<RefCell<Option<RunningBudget>>>,
+    default_memory_limit: usize,
+    memory_limits: HashMap<i32, usize>,
+    /// How much memory each running program holds. All programs share one Lua heap, so this
+    /// is only an approximation: how much the heap grew while the program ran, less what was
+    /// freed while it ran. Garbage stays charged to the program that made it until it is
+    /// collected during that program's run, and a program is never credited below zero for
+    /// collecting other programs' garbage.
+    program_memory: RefCell<HashMap<i32, i64>>,
+    /// The subscription whose callback is running, which owns anything it claims.
+    current_subscription: Rc<Cell<Option<SubscriptionId>>>,
 }
 impl SourceCodeManager {
     pub fn new(source_code_folder_path: String) -> SourceCodeManager {
+        let lua_state = Lua::new();
+        // Every string shares one metatable, so hide it from programs: one of them changing
+        // `getmetatable("").__index` would change string methods for all of them
+        lua_state
+            .load(r#"getmetatable("").__metatable = false"#)
+            .exec()
+            .unwrap();
+        // The same goes for the methods of the files `io` opens
+        lua_state
+            .load("getmetatable(io.stdout).__metatable = false")
+            .exec()
+            .unwrap();
+        let running_budget: Rc<RefCell<Option<RunningBudget>>> = Rc::new(RefCell::new(None));
+        let hook_budget = Rc::clone(&running_budget);
+        lua_state
+            .set_hook(
+                HookTriggers {
+                    on_calls: false,
+                    on_returns: false,
+                    every_line: false,
+                    every_nth_instruction: Some(INSTRUCTIONS_PER_BUDGET_CHECK),
+                },
+                move |_, _| {
+                    if let Some(running) = hook_budget.borrow_mut().as_mut() {
+                        running.instructions_run += INSTRUCTIONS_PER_BUDGET_CHECK as u64;
+                        running.check().map_err(LuaError::external)?;
+                    }
+                    Ok(())
+                },
+            )
+            .expect("cannot set Lua budget hook");
         SourceCodeManager {
             source_code_folder_path,
-            lua_state: Lua::new(),
+            lua_state,
+            program_environments: HashMap::new(),
             script_paths: HashMap::new(),
             script_source_codes: HashMap::new(),
+            max_update_iterations: 100,
+            running_programs: HashSet::new(),
+            boot_programs: vec![0],
+            program_last_seen: HashMap::new(),
+            stop_grace_period: Duration::from_secs(2),
+            quarantined_programs: HashSet::new(),
+            capabilities: HashMap::new(),
+            privileged_programs: vec![0],
+            default_execution_budget: ExecutionBudget::default(),
+            execution_budgets: HashMap::new(),
+            running_budget,
+            default_memory_limit: 64 * 1024 * 1024,
+            memory_limits: HashMap::new(),
+            program_memory: RefCell::new(HashMap::new()),
+            current_subscription: Rc::new(Cell::new(None)),
             // subscriptions: vec![],
         }
     }
 
+    /// Caps how many rounds of subscriptions `update` runs before giving up on a cycle.
+    pub fn set_max_update_iterations(&mut self, max_update_iterations: usize) {
+        self.max_update_iterations = max_update_iterations;
+    }
+
+    pub fn set_default_execution_budget(&mut self, budget: ExecutionBudget) {
+        self.default_execution_budget = budget;
+    }
+
+    /// Gives one program a different budget than the default, e.g. for heavy rendering.
+    pub fn set_execution_budget(&mut self, program_id: i32, budget: ExecutionBudget) {
+        self.execution_budgets.insert(program_id, budget);
+    }
+
+    pub fn execution_budget(&self, program_id: i32) -> ExecutionBudget {
+        *self
+            .execution_budgets
+            .get(&program_id)
+            .unwrap_or(&self.default_execution_budget)
+    }
+
+    pub fn set_default_memory_limit(&mut self, bytes: usize) {
+        self.default_memory_limit = bytes;
+    }
+
+    pub fn set_memory_limit(&mut self, program_id: i32, bytes: usize) {
+        self.memory_limits.insert(program_id, bytes);
+    }
+
+    pub fn memory_limit(&self, program_id: i32) -> usize {
+        *self
+            .memory_limits
+            .get(&program_id)
+            .unwrap_or(&self.default_memory_limit)
+    }
+
+    /// Runs `f` with the hook enforcing the program's budget and the Lua heap limited to
+    /// what the program has left of its memory limit. A program that went over its budget
+    /// fails even if it caught the error itself.
+    fn run_with_budget<R>(&self, program_id: i32, f: impl FnOnce() -> Result<R>) -> Result<R> {
+        *self.running_budget.borrow_mut() = Some(RunningBudget {
+            budget: self.execution_budget(program_id),
+            started: Instant::now(),
+            instructions_run: 0,
+            exceeded: None,
+        });
+        let memory_limit = self.memory_limit(program_id);
+        let used_by_program = *self.program_memory.borrow().get(&program_id).unwrap_or(&0);
+        let used_before = self.lua_state.used_memory();
+        self.lua_state
+            .set_memory_limit(used_before + memory_limit.saturating_sub(used_by_program as usize))
+            .unwrap();
+        let result = f();
+        self.lua_state.set_memory_limit(0).unwrap();
+        let used_after = self.lua_state.used_memory();
+        let mut program_memory = self.program_memory.borrow_mut();
+        let used = program_memory.entry(program_id).or_insert(0);
+        *used = (*used + used_after as i64 - used_before as i64).max(0);
+        std::mem::drop(program_memory);
+        let running = self.running_budget.borrow_mut().take();
+        match (running.and_then(|running| running.exceeded), result) {
+            (Some(exceeded), Ok(_)) => Err(LuaError::external(exceeded)),
+            (_, Err(e)) if is_memory_error(&e) => Err(LuaError::external(MemoryLimitExceeded {
+                limit: memory_limit,
+            })),
+            (_, result) => result,
+        }
+    }
+
+    pub fn grant_capability(&mut self, program_id: i32, capability: Capability) {
+        self.capabilities
+            .entry(program_id)
+            .or_insert_with(HashSet::new)
+            .insert(capability);
+    }
+
+    /// The capabilities granted to a program by configuration or by a privileged program
+    /// claiming `program N may use filesystem`. They are looked up when the program starts.
+    pub fn program_capabilities(&self, program_id: i32, db: &Database) -> HashSet<Capability> {
+        let mut capabilities = self
+            .capabilities
+            .get(&program_id)
+            .cloned()
+            .unwrap_or_default();
+        for privileged_id in self.privileged_programs.iter() {
+            let grants = db.select(&vec![format!(
+                "#{} program {} may use $capability",
+                privileged_id, program_id
+            )]);
+            for grant in grants {
+                let name = grant.result[0].term.to_string();
+                match Capability::from_name(&name) {
+                    Some(capability) => {
+                        capabilities.insert(capability);
+                    }
+                    None => println!("Program {} can't be granted {}", program_id, name),
+                }
+            }
+        }
+        capabilities
+    }
+
+    /// A program's globals: the sandbox plus what its capabilities allow.
+    fn sandbox_environment<'lua>(
+        lua: &'lua Lua,
+        capabilities: &HashSet<Capability>,
+    ) -> Result<Table<'lua>> {
+        let globals = lua.globals();
+        let environment = lua.create_table()?;
+        for name in SANDBOX_GLOBALS.iter() {
+            environment.set(*name, globals.get::<_, Value>(*name)?)?;
+        }
+        for name in SANDBOX_LIBRARIES.iter() {
+            environment.set(*name, copy_table(lua, &globals.get::<_, Table>(*name)?)?)?;
+        }
+        let os: Table = globals.get("os")?;
+        let sandbox_os = lua.create_table()?;
+        let os_functions = SANDBOX_OS_FUNCTIONS
+            .iter()
+            .chain(capabilities.iter().flat_map(|c| c.os_functions().iter()));
+        for name in os_functions {
+            sandbox_os.set(*name, os.get::<_, Value>(*name)?)?;
+        }
+        environment.set("os", sandbox_os)?;
+        let io_functions: Vec<&str> = capabilities
+            .iter()
+            .flat_map(|c| c.io_functions().iter().cloned())
+            .collect();
+        if !io_functions.is_empty() {
+            let io: Table = globals.get("io")?;
+            let sandbox_io = lua.create_table()?;
+            for name in io_functions {
+                sandbox_io.set(name, io.get::<_, Value>(name)?)?;
+            }
+            environment.set("io", sandbox_io)?;
+        }
+        if capabilities.contains(&Capability::Network) {
+            let require = lua.create_function(|lua, module: String| {
+                if !is_network_module(&module) {
+                    return Err(LuaError::external(format!(
+                        "the network capability doesn't allow requiring {}",
+                        module
+                    )));
+                }
+                let require: Function = lua.globals().get("require")?;
+                // Modules are loaded once and shared, so each caller gets its own copy
+                match require.call::<_, Value>(module)? {
+                    Value::Table(module) => Ok(Value::Table(copy_table(lua, &module)?)),
+                    other => Ok(other),
+                }
+            })?;
+            environment.set("require", require)?;
+        }
+        // Lua runs finalizers with the instruction hook off, where no budget can stop them
+        let setmetatable = lua.create_function(|lua, (table, metatable): (Table, Value)| {
+            if let Value::Table(metatable) = &metatable {
+                if !matches!(metatable.raw_get::<_, Value>("__gc")?, Value::Nil) {
+                    return Err(LuaError::external("programs can't set __gc metamethods"));
+                }
+            }
+            let setmetatable: Function = lua.globals().get("setmetatable")?;
+            setmetatable.call::<_, Table>((table, metatable))
+        })?;
+        environment.set("setmetatable", setmetatable)?;
+        environment.set("_G", environment.clone())?;
+        Ok(environment)
+    }
+
+    pub fn set_privileged_programs(&mut self, program_ids: Vec<i32>) {
+        self.privileged_programs = program_ids;
+    }
+
     fn get_program_id_from_filename(filename: &String) -> Option<i32> {
         lazy_static! {
-            static ref RE: Regex = Regex::new(r#"(\./scripts/(\d+)(?:__.+)*\.lua$)"#).unwrap();
+            static ref RE: Regex = Regex::new(r#"(?:^|/)(\d+)(?:__[^/]+)*\.lua$"#).unwrap();
+        }
+        let id = RE.captures_iter(filename).last()?.get(1)?.as_str();
+        match id.parse::<i32>() {
+            Ok(program_id) => Some(program_id),
+            Err(e) => {
+                println!("Skipping {}: bad program id {}: {}", filename, id, e);
+                None
+            }
         }
-        RE.captures_iter(filename).last().and_then(|cap| {
-            cap.get(2)
-                .and_then(|id| Some(id.as_str().parse::<i32>().unwrap()))
-        })
+    }
+
+    /// The program id and path of every script in the scripts folder.
+    fn script_files(&self) -> Vec<(i32, String)> {
+        let paths = match fs::read_dir(&self.source_code_folder_path) {
+            Ok(paths) => paths,
+            Err(e) => {
+                println!("Cannot read {}: {}", self.source_code_folder_path, e);
+                return vec![];
+            }
+        };
+        let mut script_files: Vec<(i32, String)> = vec![];
+        for path in paths {
+            let file_path = match path {
+                Ok(entry) => entry.path().display().to_string(),
+                Err(e) => {
+                    println!(
+                        "Cannot read an entry of {}: {}",
+                        self.source_code_folder_path, e
+                    );
+                    continue;
+                }
+            };
+            if let Some(program_id) = SourceCodeManager::get_program_id_from_filename(&file_path) {
+                script_files.push((program_id, file_path));
+            }
+        }
+        script_files.sort();
+        script_files
+    }
+
+    /// Remembers a program's source and replaces its `#00 N source code ...` fact.
+    fn load_source_code(
+        &mut self,
+        program_id: i32,
+        file_path: String,
+        source_code: String,
+        db: &mut Database,
+    ) {
+        db.retract(&format!("#00 {} source code %", program_id))
+            .unwrap();
+        // New source gets a fresh start
+        db.retract(&format!("#00 program {} has error %", program_id))
+            .unwrap();
+        self.quarantined_programs.remove(&program_id);
+        let terms: Vec<Term> = vec![
+            Term::Id("00".to_string()),
+            Term::Integer(program_id.into()),
+            Term::Text("source".to_string()),
+            Term::Text("code".to_string()),
+            Term::Text(source_code.clone()),
+        ];
+        db.claim(Fact::from_terms(&terms[..]));
+        self.script_paths.insert(program_id, file_path);
+        self.script_source_codes.insert(program_id, source_code);
     }
 
     // pub fn init(&mut self, db: &'static mut Database) {
     pub fn init(&mut self, static_db: &'static Mutex<Database>) {
         let mut db = static_db.lock().unwrap();
-        let paths = fs::read_dir("./scripts").unwrap();
-        for path in paths {
-            let file_path = path.unwrap().path().display().to_string();
+        for (program_id, file_path) in self.script_files() {
             println!("Name: {}", file_path);
-            if let Some(program_id) = SourceCodeManager::get_program_id_from_filename(&file_path) {
-                let source_code =
-                    fs::read_to_string(&file_path).expect("Should have been able to read the file");
-                self.script_paths.insert(program_id, file_path);
-                self.script_source_codes
-                    .insert(program_id, source_code.clone());
-                let terms: Vec<Term> = vec![
-                    Term::Id("00".to_string()),
-                    Term::Text(format!("{}", program_id)),
-                    Term::Text("source".to_string()),
-                    Term::Text("code".to_string()),
-                    Term::Text(source_code.clone()),
-                ];
-                db.claim(Fact::from_terms(&terms[..]));
-            }
+            let source_code =
+                fs::read_to_string(&file_path).expect("Should have been able to read the file");
+            self.load_source_code(program_id, file_path, source_code, &mut db);
         }
 
         // self.init_lua_state(db);
 
         std::mem::drop(db);
 
-        // run boot program automatically
-        self.run_program(0, &static_db);
-        self.run_program(1, &static_db);
-        self.run_program(2, &static_db);
-        self.run_program(3, &static_db);
-        self.run_program(4, &static_db);
-        self.run_program(5, &static_db);
-        self.run_program(6, &static_db);
+        // run boot program automatically, which then starts the programs it says are out
+        for program_id in self.boot_programs.clone() {
+            self.run_program(program_id, &static_db);
+        }
+        self.update_running_programs(&static_db);
+    }
+
+    pub fn is_running(&self, program_id: i32) -> bool {
+        self.running_programs.contains(&program_id)
+    }
+
+    pub fn is_quarantined(&self, program_id: i32) -> bool {
+        self.quarantined_programs.contains(&program_id)
+    }
+
+    /// How long a program can go unseen before it is stopped, so a hand passing over a
+    /// paper program doesn't restart it.
+    pub fn set_stop_grace_period(&mut self, stop_grace_period: Duration) {
+        self.stop_grace_period = stop_grace_period;
+    }
+
+    /// Starts the programs named by `program N at ...` facts, from the camera or the boot
+    /// program, and stops running programs that haven't been seen for the grace period.
+    /// Boot programs are always left running.
+    pub fn update_running_programs(&mut self, static_db: &'static Mutex<Database>) {
+        let db = static_db.lock().unwrap();
+        let seen_program_ids: Vec<i32> = db
+            .select(&vec!["$ program $id at %".to_string()])
+            .iter()
+            .filter_map(|r| r.result[0].term.as_f64())
+            .map(|id| id as i32)
+            .collect();
+        std::mem::drop(db);
+        let now = Instant::now();
+        for program_id in seen_program_ids.iter().cloned() {
+            self.program_last_seen.insert(program_id, now);
+            if !self.is_running(program_id)
+                && !self.is_quarantined(program_id)
+                && self.script_source_codes.contains_key(&program_id)
+            {
+                println!("Starting program {}", program_id);
+                self.run_program(program_id, static_db);
+            }
+        }
+        let mut unseen_program_ids: Vec<i32> = self
+            .running_programs
+            .iter()
+            .filter(|id| !self.boot_programs.contains(id) && !seen_program_ids.contains(id))
+            .filter(|id| match self.program_last_seen.get(id) {
+                Some(last_seen) => now.duration_since(*last_seen) >= self.stop_grace_period,
+                None => true,
+            })
+            .cloned()
+            .collect();
+        unseen_program_ids.sort();
+        for program_id in unseen_program_ids {
+            println!("Stopping program {}", program_id);
+            self.stop_program(program_id, static_db);
+            self.program_last_seen.remove(&program_id);
+        }
+    }
+
+    /// Picks up scripts that were added, changed or deleted since they were loaded. Changed
+    /// programs that were running are stopped and run again with the new source; deleted
+    /// ones are stopped. Returns the ids of the programs whose source changed.
+    pub fn reload_changed_scripts(&mut self, static_db: &'static Mutex<Database>) -> Vec<i32> {
+        let mut changed_program_ids: Vec<i32> = vec![];
+        let script_files = self.script_files();
+        for (program_id, file_path) in script_files.iter() {
+            let source_code = match fs::read_to_string(file_path) {
+                Ok(source_code) => source_code,
+                Err(e) => {
+                    println!("Cannot read {}: {}", file_path, e);
+                    continue;
+                }
+            };
+            if self.script_source_codes.get(program_id) == Some(&source_code) {
+                continue;
+            }
+            println!("Reloading program {} from {}", program_id, file_path);
+            // Quarantined boot programs are run again too, since nothing else would start them
+            let restart = self.running_programs.contains(program_id)
+                || self.boot_programs.contains(program_id);
+            self.stop_program(*program_id, static_db);
+            let mut db = static_db.lock().unwrap();
+            self.load_source_code(*program_id, file_path.clone(), source_code, &mut db);
+            std::mem::drop(db);
+            if restart {
+                self.run_program(*program_id, static_db);
+            }
+            changed_program_ids.push(*program_id);
+        }
+        let deleted_program_ids: Vec<i32> = self
+            .script_source_codes
+            .keys()
+            .filter(|id| !script_files.iter().any(|(program_id, _)| program_id == *id))
+            .cloned()
+            .collect();
+        for program_id in deleted_program_ids {
+            println!("Program {} was deleted", program_id);
+            self.stop_program(program_id, static_db);
+            self.quarantined_programs.remove(&program_id);
+            let mut db = static_db.lock().unwrap();
+            db.retract(&format!("#00 {} source code %", program_id))
+                .unwrap();
+            db.retract(&format!("#00 program {} has error %", program_id))
+                .unwrap();
+            self.script_paths.remove(&program_id);
+            self.script_source_codes.remove(&program_id);
+            changed_program_ids.push(program_id);
+        }
+        changed_program_ids
+    }
+
+    /// Retracts everything a program claimed and drops its subscriptions.
+    pub fn stop_program(&mut self, program_id: i32, static_db: &'static Mutex<Database>) {
+        let mut db = static_db.lock().unwrap();
+        db.remove_subscriptions_by_program(&program_id.to_string());
+        db.retract_owned(&program_id.to_string(), "%").unwrap();
+        std::mem::drop(db);
+        self.running_programs.remove(&program_id);
+        if let Some(environment) = self.program_environments.remove(&program_id) {
+            self.lua_state.remove_registry_value(environment).unwrap();
+        }
+        // Frees the callbacks of the subscriptions that were just dropped, and whatever the
+        // program was holding on to
+        self.lua_state.expire_registry_values();
+        self.lua_state.gc_collect().unwrap();
+        self.program_memory.borrow_mut().remove(&program_id);
     }
 
-    pub fn update(&mut self, static_db: &'static Mutex<Database>) {
-        self.run_subscriptions(&static_db);
+    /// Stops a program that raised an error and keeps it stopped until its script changes.
+    /// The error is claimed as `#00 program N has error "..."` so other programs can show it.
+    fn quarantine_program(
+        &mut self,
+        program_id: i32,
+        error: &LuaError,
+        static_db: &'static Mutex<Database>,
+    ) {
+        println!("Exception in program {}: {}", program_id, error);
+        self.stop_program(program_id, static_db);
+        self.quarantined_programs.insert(program_id);
+        let mut db = static_db.lock().unwrap();
+        db.retract(&format!("#00 program {} has error %", program_id))
+            .unwrap();
+        db.claim(Fact::from_terms(&[
+            Term::Id("00".to_string()),
+            Term::Text("program".to_string()),
+            Term::Integer(program_id.into()),
+            Term::Text("has".to_string()),
+            Term::Text("error".to_string()),
+            Term::Text(error.to_string()),
+        ]));
+    }
 
-        // self.run_lua(db, )
-        // handler.call::<_, ()>(results)
-        // self.lua_state.load(source_code).exec()
+    /// Runs subscriptions until none of their results change, so chains of programs
+    /// reacting to each other settle within one update. Returns the number of rounds.
+    pub fn update(
+        &mut self,
+        static_db: &'static Mutex<Database>,
+    ) -> std::result::Result<usize, ConvergenceError> {
+        let mut program_ids: Vec<String> = vec![];
+        for iteration in 0..self.max_update_iterations {
+            program_ids = self.run_subscriptions(&static_db);
+            if program_ids.is_empty() {
+                return Ok(iteration);
+            }
+        }
+        program_ids.sort();
+        program_ids.dedup();
+        Err(ConvergenceError {
+            iterations: self.max_update_iterations,
+            program_ids,
+        })
     }
 
-    fn run_subscriptions(&mut self, static_db: &'static Mutex<Database>) {
+    /// Runs the callbacks of subscriptions whose results changed and returns the ids of
+    /// the programs they belong to.
+    fn run_subscriptions(&mut self, static_db: &'static Mutex<Database>) -> Vec<String> {
         // how to iterate over subscriptions when it will also be modified?
         let mut db = static_db.lock().unwrap();
-        let mut stuff: Vec<(LuaFunction, Table)> = vec![];
-        for sub in &db.subscriptions {
-            println!("running a subscription");
+        let mut stuff: Vec<(SubscriptionId, String, LuaFunction, ResultChanges)> = vec![];
+        let mut program_ids: Vec<String> = vec![];
+        for i in 0..db.subscriptions.len() {
+            let results = db.select(&db.subscriptions[i].query_parts);
+            let sub = &mut db.subscriptions[i];
+            let changes = match sub.update_results(results) {
+                Some(changes) => changes,
+                None => continue,
+            };
+            program_ids.push(sub.program_source_id.to_owned());
             let handler = &sub.callback_func;
             let handler: Function = self
                 .lua_state
                 .registry_value(&handler)
                 .expect("cannot get Lua handler");
-            let results = self.lua_state.create_table().unwrap();
-            for v in db.select(&sub.query_parts) {
-                let result = self.lua_state.create_table().unwrap();
-                for r in v.result {
-                    result.set(r.variable_name, r.term.to_string()).unwrap();
-                }
-                results.set(1, result).unwrap();
-            }
-
-            stuff.push((handler, results));
+            stuff.push((sub.id, sub.program_source_id.to_owned(), handler, changes));
 
             // handler.call::<_, ()>(results);
             // self.lua_state.scope(|scope| {
@@ -156,10 +960,59 @@ impl SourceCodeManager {
             // }).unwrap();
             // self.run_lua(&sub.program_source_id, db, || handler.call::<_, ()>(results));
         }
+        // The results become Lua tables only once the database is unlocked, since allocating
+        // can run Lua code that calls back into the database
         std::mem::drop(db);
-        for (handler, results) in stuff {
-            handler.call::<_, ()>(results);
+        let mut failures: Vec<(i32, LuaError)> = vec![];
+        for (subscription_id, program_id, handler, changes) in stuff {
+            let program_id: i32 = program_id.parse().unwrap();
+            if failures
+                .iter()
+                .any(|(failed_id, _)| *failed_id == program_id)
+            {
+                // An earlier callback of the same program failed this round
+                continue;
+            }
+            // Whatever the callback claimed last time is replaced by what it claims now, all
+            // at once so nobody sees the old facts gone and the new ones missing
+            let results = self.results_to_lua(&changes.results);
+            let added = self.results_to_lua(&changes.added);
+            let removed = self.results_to_lua(&changes.removed);
+            let mut db = static_db.lock().unwrap();
+            db.begin();
+            db.retract_subscription_facts(subscription_id);
+            std::mem::drop(db);
+            self.current_subscription.set(Some(subscription_id));
+            let result = self.run_with_budget(program_id, || {
+                handler.call::<_, ()>((results, added, removed))
+            });
+            self.current_subscription.set(None);
+            let mut db = static_db.lock().unwrap();
+            match result {
+                Ok(()) => db.commit(),
+                Err(e) => {
+                    db.rollback();
+                    failures.push((program_id, e));
+                }
+            }
+        }
+        for (program_id, e) in failures {
+            self.quarantine_program(program_id, &e, static_db);
+        }
+        program_ids
+    }
+
+    fn results_to_lua(&self, query_results: &[QueryResult]) -> Table {
+        let results = self.lua_state.create_table().unwrap();
+        for (i, v) in query_results.iter().enumerate() {
+            let result = self.lua_state.create_table().unwrap();
+            for r in v.result.iter() {
+                let value = term_to_lua(&self.lua_state, &r.term).unwrap();
+                result.set(r.variable_name.as_str(), value).unwrap();
+            }
+            results.set(i + 1, result).unwrap();
         }
+        results
     }
 
     // fn init_lua_state<'a, 'b: 'a>(&self, program_id: i32, scope: &'a mlua::Scope<'_, 'a>, db: &'a mut Database) {
@@ -258,57 +1111,108 @@ impl SourceCodeManager {
 
             // let v = RefCell::new(5);
 
+            let capabilities = self.program_capabilities(program_id, &static_db.lock().unwrap());
+            let environment =
+                SourceCodeManager::sandbox_environment(&self.lua_state, &capabilities).unwrap();
+
+            let current_subscription = Rc::clone(&self.current_subscription);
             let claim = self
                 .lua_state
                 .create_function_mut(move |_, va: Variadic<Fact>| {
                     let mut db = static_db.lock().unwrap();
-                    let mut fact_to_claim = Fact { terms: vec![Term::Id(program_id.to_string())] };
-                    for f in va.iter() {
-                        for term in f.terms.iter() {
-                            fact_to_claim.terms.push(term.clone());
+                    let fact_to_claim = program_fact(program_id, &va);
+                    match current_subscription.get() {
+                        Some(subscription_id) => {
+                            db.claim_for_subscription(subscription_id, fact_to_claim)
                         }
+                        None => db.claim(fact_to_claim),
                     }
-                    db.claim(fact_to_claim);
                     std::mem::drop(db);
                     Ok(())
                 })
                 .unwrap();
-            self.lua_state.globals().set("claim", claim).unwrap();
+            environment.set("claim", claim).unwrap();
 
-            let retract = self
+            let claim_for = self
                 .lua_state
-                .create_function_mut(|_, fact_string: String| {
+                .create_function_mut(move |_, (seconds, va): (f64, Variadic<Fact>)| {
+                    if !(seconds.is_finite() && seconds >= 0.0) {
+                        return Err(LuaError::external(format!(
+                            "claim_for needs a duration in seconds, got {}",
+                            seconds
+                        )));
+                    }
                     let mut db = static_db.lock().unwrap();
-                    db.retract(&fact_string);
-                    std::mem::drop(db);
+                    db.claim_with_expiry(
+                        program_fact(program_id, &va),
+                        Expiry::After(Duration::from_secs_f64(seconds)),
+                    );
                     Ok(())
                 })
                 .unwrap();
-            self.lua_state.globals().set("retract", retract).unwrap();
+            environment.set("claim_for", claim_for).unwrap();
+
+            let claim_for_ticks = self
+                .lua_state
+                .create_function_mut(move |_, (ticks, va): (u64, Variadic<Fact>)| {
+                    let mut db = static_db.lock().unwrap();
+                    db.claim_with_expiry(program_fact(program_id, &va), Expiry::Ticks(ticks));
+                    Ok(())
+                })
+                .unwrap();
+            environment.set("claim_for_ticks", claim_for_ticks).unwrap();
+
+            // Programs can only retract their own facts unless they are privileged
+            let retract = self
+                .lua_state
+                .create_function_mut(move |_, fact_string: String| {
+                    let mut db = static_db.lock().unwrap();
+                    db.retract_owned(&program_id.to_string(), &fact_string)
+                        .map_err(LuaError::external)
+                })
+                .unwrap();
+            environment.set("retract", retract).unwrap();
+
+            let privileged = self.privileged_programs.contains(&program_id);
+            let retract_any = self
+                .lua_state
+                .create_function_mut(move |_, fact_string: String| {
+                    if !privileged {
+                        return Err(LuaError::external(format!(
+                            "program {} is not allowed to retract other programs' facts",
+                            program_id
+                        )));
+                    }
+                    let mut db = static_db.lock().unwrap();
+                    db.retract(&fact_string).map_err(LuaError::external)
+                })
+                .unwrap();
+            environment.set("retract_any", retract_any).unwrap();
 
             let cleanup = self
                 .lua_state
                 .create_function_mut(move |_, ()| {
                     let mut db = static_db.lock().unwrap();
-                    db.retract(&format!("${} %", program_id));
+                    db.retract_owned(&program_id.to_string(), "%").unwrap();
                     db.remove_subscriptions_by_program(&program_id.to_string());
                     std::mem::drop(db);
                     Ok(())
                 })
                 .unwrap();
-            self.lua_state.globals().set("cleanup", cleanup).unwrap();
+            environment.set("cleanup", cleanup).unwrap();
 
             // This function probaby doesn't need to be within a scope?
             let when_func = self
                 .lua_state
                 .create_function_mut(
                     move |lua, (query_parts, callback_func): (Vec<String>, Function)| {
-                        let mut db = static_db.lock().unwrap();
+                        Query::parse(&query_parts).map_err(LuaError::external)?;
                         let handler = lua
                             .create_registry_value(callback_func)
                             .expect("cannot store Lua handler");
+                        let mut db = static_db.lock().unwrap();
                         // db.subscriptions.push(Subscription::new(&program_id.to_string(), &query_parts, handler));
-                        db.subscriptions.push(Subscription::new(
+                        db.add_subscription(Subscription::new(
                             &program_id.to_string(),
                             &query_parts,
                             handler,
@@ -318,7 +1222,38 @@ impl SourceCodeManager {
                     },
                 )
                 .unwrap();
-            self.lua_state.globals().set("when", when_func).unwrap();
+            environment.set("when", when_func).unwrap();
+
+            let explain = self
+                .lua_state
+                .create_function(move |_, query_parts: Vec<String>| {
+                    let db = static_db.lock().unwrap();
+                    let plan = db.explain(&query_parts).map_err(LuaError::external)?;
+                    Ok(plan.to_string())
+                })
+                .unwrap();
+            environment.set("explain", explain).unwrap();
+
+            let provenance = self
+                .lua_state
+                .create_function(move |lua, pattern: String| {
+                    let db = static_db.lock().unwrap();
+                    let facts = db.provenance(&pattern).map_err(LuaError::external)?;
+                    std::mem::drop(db);
+                    let results = lua.create_table()?;
+                    for (i, (fact, metadata)) in facts.iter().enumerate() {
+                        let entry = lua.create_table()?;
+                        entry.set("fact", fact.to_string())?;
+                        entry.set("owner", metadata.owner.clone())?;
+                        entry.set("subscription", metadata.subscription_id)?;
+                        entry.set("tick", metadata.created_tick)?;
+                        entry.set("source", metadata.source.name())?;
+                        results.set(i + 1, entry)?;
+                    }
+                    Ok(results)
+                })
+                .unwrap();
+            environment.set("provenance", provenance).unwrap();
 
             // self.lua_state.globals().set(
             //     "when",
@@ -353,12 +1288,739 @@ impl SourceCodeManager {
 
             // let illumination_constructor = self.lua_state.create_function_mut(|_, ()| Ok(Illumination { graphics: vec![] })).unwrap();
             // self.lua_state.globals().set("Illumination", illumination_constructor).unwrap();
-            self.lua_state.globals().set("Illumination", self.lua_state.create_proxy::<Illumination>().unwrap()).unwrap();
+            environment
+                .set(
+                    "Illumination",
+                    self.lua_state.create_proxy::<Illumination>().unwrap(),
+                )
+                .unwrap();
 
             // self.run_lua(&program_id.to_string(), db, || self.lua_state.load(source_code).exec());
-            self.lua_state.load(source_code).exec().unwrap();
+            let environment_key = self
+                .lua_state
+                .create_registry_value(environment.clone())
+                .unwrap();
+            if let Some(old_environment) = self
+                .program_environments
+                .insert(program_id, environment_key)
+            {
+                self.lua_state
+                    .remove_registry_value(old_environment)
+                    .unwrap();
+            }
+            self.running_programs.insert(program_id);
+            static_db.lock().unwrap().begin();
+            // Named after the script so errors point at its lines
+            let chunk_name = match self.script_paths.get(&program_id) {
+                Some(file_path) => format!("@{}", file_path),
+                None => format!("=program {}", program_id),
+            };
+            let result = self
+                .lua_state
+                .load(source_code)
+                .set_name(chunk_name)
+                .and_then(|chunk| chunk.set_environment(environment))
+                .and_then(|chunk| self.run_with_budget(program_id, || chunk.exec()));
+            let mut db = static_db.lock().unwrap();
+            match result {
+                Ok(()) => db.commit(),
+                Err(e) => {
+                    db.rollback();
+                    std::mem::drop(db);
+                    self.quarantine_program(program_id, &e, static_db);
+                }
+            }
         } else {
             println!("Exception when running program {program_id}: program ID not found");
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fact_from_lua_values() {
+        let lua = Lua::new();
+        let claimed: Variadic<Fact> = lua
+            .load(
+                r#"return "program", 5, "at", {x=1, y=2.5}, {"sensor", 7, true}, {"", "a b"},
+                    {}, {"text", "hello"}"#,
+            )
+            .eval()
+            .unwrap();
+        let terms: Vec<Term> = claimed.iter().flat_map(|f| f.terms.clone()).collect();
+        assert_eq!(
+            terms,
+            vec![
+                Term::Text("program".to_string()),
+                Term::Integer(5),
+                Term::Text("at".to_string()),
+                Term::Json(serde_json::json!({"x": 1, "y": 2.5})),
+                Term::Text("sensor".to_string()),
+                Term::Integer(7),
+                Term::Boolean(true),
+                Term::Text("a b".to_string()),
+                Term::Json(serde_json::json!([])),
+                Term::Text("text".to_string()),
+                Term::Text("hello".to_string()),
+            ]
+        );
+
+        let err = lua
+            .load("return function() end")
+            .eval::<Fact>()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            LuaError::FromLuaConversionError {
+                from: "function",
+                ..
+            }
+        ));
+    }
+
+    fn test_db() -> &'static Mutex<Database> {
+        Box::leak(Box::new(Mutex::new(Database::new())))
+    }
+
+    fn load_programs(manager: &mut SourceCodeManager, programs: &[(i32, &str)]) {
+        for (program_id, source_code) in programs.iter() {
+            manager
+                .script_source_codes
+                .insert(*program_id, source_code.to_string());
+        }
+    }
+
+    #[test]
+    fn update_runs_to_fixed_point() {
+        let static_db = test_db();
+        let mut manager = SourceCodeManager::new("./scripts".to_string());
+        load_programs(
+            &mut manager,
+            &[
+                (1, r#"claim("fox is red")"#),
+                (
+                    2,
+                    r##"when({"$ $animal is red"}, function (results)
+                        for index, result in ipairs(results) do
+                            claim("red animal seen")
+                        end
+                    end)"##,
+                ),
+                (
+                    3,
+                    r##"when({"$ red animal seen"}, function (results)
+                        if #results > 0 then claim("heard about it") end
+                    end)"##,
+                ),
+                (
+                    4,
+                    r##"when({"$ toggle"}, function (results)
+                        if #results == 0 then claim("toggle") end
+                    end)"##,
+                ),
+            ],
+        );
+        for program_id in 1..=3 {
+            manager.run_program(program_id, static_db);
+        }
+        assert!(manager.update(static_db).is_ok());
+        let db = static_db.lock().unwrap();
+        assert_eq!(db.select(&vec!["$ heard about it".to_string()]).len(), 1);
+        std::mem::drop(db);
+
+        manager.set_max_update_iterations(10);
+        manager.run_program(4, static_db);
+        let err = manager.update(static_db).unwrap_err();
+        assert_eq!(err.iterations, 10);
+        assert_eq!(err.program_ids, vec!["4".to_string()]);
+    }
+
+    #[test]
+    fn retract_is_scoped_to_the_program() {
+        let static_db = test_db();
+        let mut db = static_db.lock().unwrap();
+        db.claim(Fact::from_string("#0cv program 3 at 0 0"));
+        db.claim(Fact::from_string("#1 fox is red"));
+        db.claim(Fact::from_string("#2 fox is red"));
+        std::mem::drop(db);
+        let mut manager = SourceCodeManager::new("./scripts".to_string());
+        load_programs(
+            &mut manager,
+            &[
+                (
+                    1,
+                    r#"claim("removed", retract("$ fox is red"))
+                    claim("allowed", (pcall(retract_any, "$ %")))"#,
+                ),
+                (0, r##"claim("removed", retract_any("#0cv %"))"##),
+            ],
+        );
+        manager.run_program(1, static_db);
+        manager.run_program(0, static_db);
+
+        let db = static_db.lock().unwrap();
+        let facts: Vec<String> = db
+            .select(&vec!["%fact".to_string()])
+            .into_iter()
+            .map(|r| r.result[0].term.to_string())
+            .collect();
+        assert_eq!(
+            facts,
+            vec![
+                "#2 fox is red",
+                "#1 removed 1",
+                "#1 allowed false",
+                "#0 removed 1"
+            ]
+        );
+    }
+
+    #[test]
+    fn retract_counts_facts_claimed_in_the_same_run() {
+        let static_db = test_db();
+        let mut manager = SourceCodeManager::new("./scripts".to_string());
+        load_programs(
+            &mut manager,
+            &[(
+                1,
+                r##"claim("tmp")
+                claim("removed", retract("$ tmp"))
+                when({"$ fox is red"}, function (results)
+                    claim("scratch")
+                    claim("removed in callback", retract("$ scratch"))
+                end)"##,
+            )],
+        );
+        manager.run_program(1, static_db);
+        assert!(manager.update(static_db).is_ok());
+
+        let db = static_db.lock().unwrap();
+        let facts: Vec<String> = db
+            .select(&vec!["#1 %rest".to_string()])
+            .into_iter()
+            .map(|r| r.result[0].term.to_string())
+            .collect();
+        assert_eq!(facts, vec!["removed 1", "removed in callback 1"]);
+    }
+
+    #[test]
+    fn programs_cannot_change_string_methods_for_others() {
+        let static_db = test_db();
+        let mut manager = SourceCodeManager::new("./scripts".to_string());
+        load_programs(
+            &mut manager,
+            &[
+                (
+                    1,
+                    r#"claim("metatable", getmetatable(""))
+                    claim("changed", (pcall(function ()
+                        getmetatable("").__index.upper = function () return "HACKED" end
+                    end)))"#,
+                ),
+                (2, r#"claim("upper", ("abc"):upper())"#),
+            ],
+        );
+        manager.run_program(1, static_db);
+        manager.run_program(2, static_db);
+
+        let db = static_db.lock().unwrap();
+        let facts: Vec<String> = db
+            .select(&vec!["$ %rest".to_string()])
+            .into_iter()
+            .map(|r| r.result[0].term.to_string())
+            .collect();
+        assert_eq!(facts, vec!["metatable false", "changed false", "upper ABC"]);
+    }
+
+    #[test]
+    fn reload_changed_scripts() {
+        let folder = std::env::temp_dir().join(format!("progspace-scripts-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        let script = |name: &str, source_code: &str| {
+            fs::write(folder.join(name), source_code).unwrap();
+        };
+        script("1__fox.lua", r#"claim("fox is red")"#);
+        script(
+            "2__listener.lua",
+            r#"when({"$ fox is $color"}, function (results) end)"#,
+        );
+        script("notes.txt", "not a program");
+        script("99999999999__too_big.lua", r#"claim("never runs")"#);
+
+        let static_db = test_db();
+        let mut manager = SourceCodeManager::new(folder.display().to_string());
+        manager.init(static_db);
+        manager.run_program(1, static_db);
+        manager.run_program(2, static_db);
+        assert!(manager.reload_changed_scripts(static_db).is_empty());
+
+        script("1__fox.lua", r#"claim("fox is blue")"#);
+        fs::remove_file(folder.join("2__listener.lua")).unwrap();
+        script("3__new.lua", r#"claim("new program")"#);
+        assert_eq!(manager.reload_changed_scripts(static_db), vec![1, 3, 2]);
+
+        let db = static_db.lock().unwrap();
+        let select = |query: &str| -> Vec<String> {
+            db.select(&vec![query.to_string()])
+                .into_iter()
+                .map(|r| r.result[0].term.to_string())
+                .collect()
+        };
+        assert_eq!(select("#1 fox is $color"), vec!["blue"]);
+        assert_eq!(select("#00 $id source code $"), vec!["1", "3"]);
+        assert!(select("#3 %rest").is_empty());
+        assert!(db.subscriptions.is_empty());
+        std::mem::drop(db);
+        let _ = fs::remove_dir_all(&folder);
+    }
+
+    #[test]
+    fn programs_run_while_seen() {
+        let folder =
+            std::env::temp_dir().join(format!("progspace-lifecycle-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        fs::write(
+            folder.join("0__boot.lua"),
+            r#"claim("program 1 at 0 0 1 0 1 1 0 1")"#,
+        )
+        .unwrap();
+        fs::write(folder.join("1__fox.lua"), r#"claim("fox is red")"#).unwrap();
+        fs::write(folder.join("2__unseen.lua"), r#"claim("unseen")"#).unwrap();
+
+        let static_db = test_db();
+        let mut manager = SourceCodeManager::new(folder.display().to_string());
+        manager.init(static_db);
+        assert!(manager.is_running(0) && manager.is_running(1) && !manager.is_running(2));
+
+        let mut db = static_db.lock().unwrap();
+        db.retract("#0 program 1 %").unwrap();
+        db.claim(Fact::from_string("#0cv program 2 at 0 0 1 0 1 1 0 1"));
+        std::mem::drop(db);
+        manager.set_stop_grace_period(Duration::from_secs(3600));
+        manager.update_running_programs(static_db);
+        assert!(manager.is_running(1) && manager.is_running(2));
+
+        manager.set_stop_grace_period(Duration::ZERO);
+        manager.update_running_programs(static_db);
+        assert!(!manager.is_running(1) && manager.is_running(2) && manager.is_running(0));
+        let db = static_db.lock().unwrap();
+        assert!(db.select(&vec!["$ fox is red".to_string()]).is_empty());
+        assert_eq!(db.select(&vec!["#2 unseen".to_string()]).len(), 1);
+        std::mem::drop(db);
+        let _ = fs::remove_dir_all(&folder);
+    }
+
+    #[test]
+    fn programs_have_their_own_globals() {
+        let static_db = test_db();
+        let mut manager = SourceCodeManager::new("./scripts".to_string());
+        load_programs(
+            &mut manager,
+            &[
+                (
+                    1,
+                    r##"cache = "one"
+                    when({"$ fox is red"}, function (results)
+                        if #results > 0 then claim("cache", cache) end
+                    end)"##,
+                ),
+                (2, r#"cache = "two""#),
+            ],
+        );
+        manager.run_program(1, static_db);
+        manager.run_program(2, static_db);
+        static_db
+            .lock()
+            .unwrap()
+            .claim(Fact::from_string("#0 fox is red"));
+        assert!(manager.update(static_db).is_ok());
+
+        let db = static_db.lock().unwrap();
+        let facts: Vec<String> = db
+            .select(&vec!["$ cache $value".to_string()])
+            .into_iter()
+            .map(|r| r.result[0].term.to_string())
+            .collect();
+        assert_eq!(facts, vec!["one"]);
+        assert_eq!(db.select(&vec!["#1 cache one".to_string()]).len(), 1);
+        std::mem::drop(db);
+        let cache: Option<String> = manager.lua_state.globals().get("cache").unwrap();
+        assert_eq!(cache, None);
+
+        manager.stop_program(1, static_db);
+        assert!(!manager.program_environments.contains_key(&1));
+        assert!(manager.program_environments.contains_key(&2));
+    }
+
+    #[test]
+    fn failing_programs_are_quarantined() {
+        let static_db = test_db();
+        let mut manager = SourceCodeManager::new("./scripts".to_string());
+        load_programs(
+            &mut manager,
+            &[
+                (1, "claim(\"before the error\")\nclaim(\"fox is\" .. )"),
+                (
+                    2,
+                    r##"claim("listening")
+                    when({"$ fox is red"}, function (results)
+                        claim("half done")
+                        if #results > 0 then error("no red foxes") end
+                    end)"##,
+                ),
+                (
+                    3,
+                    r#"when({"$ fox is red"}, function (results) claim("ok") end)"#,
+                ),
+            ],
+        );
+        for program_id in 1..=3 {
+            manager.run_program(program_id, static_db);
+        }
+        assert!(manager.is_quarantined(1) && !manager.is_running(1));
+        assert!(manager.update(static_db).is_ok());
+        assert!(manager.is_running(2));
+        static_db
+            .lock()
+            .unwrap()
+            .claim(Fact::from_string("#0 fox is red"));
+        assert!(manager.update(static_db).is_ok());
+        assert!(manager.is_quarantined(2) && !manager.is_running(2));
+        assert!(manager.is_running(3));
+
+        let db = static_db.lock().unwrap();
+        let errors: Vec<(String, String)> = db
+            .select(&vec!["#00 program $id has error $error".to_string()])
+            .into_iter()
+            .map(|r| (r.result[0].term.to_string(), r.result[1].term.to_string()))
+            .collect();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].0, "1");
+        assert!(errors[0].1.contains("program 1:2:"));
+        assert_eq!(errors[1].0, "2");
+        assert!(errors[1].1.contains("program 2:4: no red foxes"));
+        assert!(errors[1].1.contains("stack traceback"));
+        assert!(db.select(&vec!["#1 %".to_string()]).is_empty());
+        assert!(db.select(&vec!["#2 %".to_string()]).is_empty());
+        assert_eq!(db.select(&vec!["#3 ok".to_string()]).len(), 1);
+        assert!(db.subscriptions.iter().all(|s| s.program_source_id == "3"));
+        std::mem::drop(db);
+
+        // Being seen doesn't restart a quarantined program
+        static_db
+            .lock()
+            .unwrap()
+            .claim(Fact::from_string("#0cv program 1 at 0 0 1 0 1 1 0 1"));
+        manager.update_running_programs(static_db);
+        assert!(manager.is_quarantined(1) && !manager.is_running(1));
+    }
+
+    #[test]
+    fn programs_with_unusable_chunk_names_are_quarantined() {
+        let static_db = test_db();
+        let mut manager = SourceCodeManager::new("./scripts".to_string());
+        load_programs(&mut manager, &[(1, r#"claim("fox is red")"#)]);
+        manager
+            .script_paths
+            .insert(1, "scripts/1__bad\0name.lua".to_string());
+        manager.run_program(1, static_db);
+        assert!(manager.is_quarantined(1) && !manager.is_running(1));
+
+        let db = static_db.lock().unwrap();
+        assert!(db.select(&vec!["#1 %".to_string()]).is_empty());
+        let errors = db.select(&vec!["#00 program 1 has error $error".to_string()]);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].result[0]
+            .term
+            .to_string()
+            .contains("invalid name"));
+    }
+
+    #[test]
+    fn programs_time_out() {
+        let static_db = test_db();
+        let mut manager = SourceCodeManager::new("./scripts".to_string());
+        load_programs(
+            &mut manager,
+            &[
+                (1, "while true do end"),
+                (
+                    2,
+                    r##"when({"$ fox is red"}, function (results)
+                        pcall(function () while true do end end)
+                        claim("caught it")
+                    end)"##,
+                ),
+                (3, "while true do end"),
+                (4, r#"for i = 1, 1000 do claim("count", i) end"#),
+            ],
+        );
+        manager.set_execution_budget(
+            3,
+            ExecutionBudget {
+                instructions: u64::MAX,
+                duration: Duration::from_millis(10),
+            },
+        );
+        for program_id in 1..=4 {
+            manager.run_program(program_id, static_db);
+        }
+        static_db
+            .lock()
+            .unwrap()
+            .claim(Fact::from_string("#0 fox is red"));
+        assert!(manager.update(static_db).is_ok());
+        assert!(manager.is_quarantined(1) && manager.is_quarantined(2));
+        assert!(manager.is_quarantined(3) && manager.is_running(4));
+
+        let db = static_db.lock().unwrap();
+        let errors: Vec<String> = db
+            .select(&vec!["#00 program $id has error $error".to_string()])
+            .into_iter()
+            .map(|r| r.result[1].term.to_string())
+            .collect();
+        assert_eq!(errors.len(), 3);
+        assert!(errors[0].contains("timed out after running more than 10000000 instructions"));
+        assert!(errors[1].contains("timed out after running longer than 10ms"));
+        assert!(errors[2].contains("timed out after running more than 10000000 instructions"));
+        assert!(db.select(&vec!["#2 caught it".to_string()]).is_empty());
+        assert_eq!(db.select(&vec!["#4 count $i".to_string()]).len(), 1000);
+    }
+
+    #[test]
+    fn programs_cannot_set_finalizers() {
+        let static_db = test_db();
+        let mut manager = SourceCodeManager::new("./scripts".to_string());
+        load_programs(
+            &mut manager,
+            &[
+                (
+                    1,
+                    r#"stuck = setmetatable({}, {__gc = function () while true do end end})"#,
+                ),
+                (
+                    2,
+                    r#"local fox = setmetatable({}, {__index = {color = "red"}})
+                    claim("fox is " .. fox.color)"#,
+                ),
+            ],
+        );
+        manager.run_program(1, static_db);
+        manager.run_program(2, static_db);
+        assert!(manager.is_quarantined(1) && !manager.is_running(1));
+        assert!(manager.is_running(2));
+
+        let db = static_db.lock().unwrap();
+        assert_eq!(db.select(&vec!["#2 fox is red".to_string()]).len(), 1);
+        let errors = db.select(&vec!["#00 program 1 has error $error".to_string()]);
+        assert!(errors[0].result[0]
+            .term
+            .to_string()
+            .contains("programs can't set __gc metamethods"));
+    }
+
+    #[test]
+    fn programs_have_memory_limits() {
+        let static_db = test_db();
+        let mut manager = SourceCodeManager::new("./scripts".to_string());
+        load_programs(
+            &mut manager,
+            &[
+                (
+                    1,
+                    r#"cache = {}
+                    for i = 1, 100000 do cache[i] = string.rep("x", 100) .. i end"#,
+                ),
+                (
+                    2,
+                    r##"cache = {}
+                    when({"$ frame $n"}, function (results)
+                        for i = 1, 1000 do table.insert(cache, string.rep("x", 100) .. i) end
+                    end)"##,
+                ),
+                (3, r#"cache = {} for i = 1, 1000 do cache[i] = i end"#),
+            ],
+        );
+        for program_id in 1..=3 {
+            manager.set_memory_limit(program_id, 1024 * 1024);
+        }
+        for program_id in 1..=3 {
+            manager.run_program(program_id, static_db);
+        }
+        assert!(manager.is_quarantined(1) && manager.is_running(2));
+        for frame in 0..20 {
+            let mut db = static_db.lock().unwrap();
+            db.retract("#0 frame %").unwrap();
+            db.claim(Fact::from_string(&format!("#0 frame {}", frame)));
+            std::mem::drop(db);
+            assert!(manager.update(static_db).is_ok());
+        }
+        assert!(manager.is_quarantined(2) && manager.is_running(3));
+
+        let db = static_db.lock().unwrap();
+        let errors: Vec<String> = db
+            .select(&vec!["#00 program $id has error $error".to_string()])
+            .into_iter()
+            .map(|r| r.result[1].term.to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "ran out of its memory limit of 1048576 bytes",
+                "ran out of its memory limit of 1048576 bytes"
+            ]
+        );
+    }
+
+    #[test]
+    fn collecting_garbage_cannot_deadlock_the_database() {
+        let static_db = test_db();
+        let mut manager = SourceCodeManager::new("./scripts".to_string());
+        load_programs(
+            &mut manager,
+            &[(
+                1,
+                r##"local ok = pcall(setmetatable, {}, {__gc = function () claim("collected") end})
+                if not ok then claim("finalizer refused") end
+                when({"$ fox $number is red"}, function (results)
+                    claim("seen", #results)
+                end)"##,
+            )],
+        );
+        manager.run_program(1, static_db);
+        let mut db = static_db.lock().unwrap();
+        for number in 0..1000 {
+            db.claim(Fact::from_string(&format!("#0 fox {} is red", number)));
+        }
+        std::mem::drop(db);
+        assert!(manager.update(static_db).is_ok());
+
+        let db = static_db.lock().unwrap();
+        assert_eq!(
+            db.select(&vec!["#1 finalizer refused".to_string()]).len(),
+            1
+        );
+        assert_eq!(db.select(&vec!["#1 seen 1000".to_string()]).len(), 1);
+        std::mem::drop(db);
+        manager.stop_program(1, static_db);
+        assert!(!manager.is_quarantined(1));
+        let db = static_db.lock().unwrap();
+        assert!(db.select(&vec!["#1 collected".to_string()]).is_empty());
+    }
+
+    #[test]
+    fn programs_are_not_credited_for_others_garbage() {
+        let static_db = test_db();
+        let mut manager = SourceCodeManager::new("./scripts".to_string());
+        load_programs(
+            &mut manager,
+            &[
+                (
+                    1,
+                    r#"garbage = {}
+                    for i = 1, 2000 do garbage[i] = string.rep("x", 1000) .. i end
+                    garbage = nil"#,
+                ),
+                (
+                    2,
+                    r##"cache = {}
+                    when({"$ frame $n"}, function (results)
+                        for i = 1, 2000 do table.insert(cache, string.rep("x", 100) .. i) end
+                    end)"##,
+                ),
+            ],
+        );
+        manager.set_memory_limit(2, 1024 * 1024);
+        manager.run_program(1, static_db);
+        manager.run_program(2, static_db);
+        // Program 2 happens to be running when program 1's garbage is collected
+        manager
+            .run_with_budget(2, || manager.lua_state.gc_collect())
+            .unwrap();
+        assert_eq!(manager.program_memory.borrow()[&2], 0);
+        assert!(manager.program_memory.borrow()[&1] > 1024 * 1024);
+
+        for frame in 0..20 {
+            let mut db = static_db.lock().unwrap();
+            db.retract("#0 frame %").unwrap();
+            db.claim(Fact::from_string(&format!("#0 frame {}", frame)));
+            std::mem::drop(db);
+            assert!(manager.update(static_db).is_ok());
+        }
+        assert!(manager.is_running(1) && manager.is_quarantined(2));
+    }
+
+    #[test]
+    fn programs_are_sandboxed() {
+        let static_db = test_db();
+        let mut manager = SourceCodeManager::new("./scripts".to_string());
+        let check = r#"claim("io", io ~= nil)
+            claim("open", io ~= nil and io.open ~= nil)
+            claim("popen", io ~= nil and io.popen ~= nil)
+            claim("execute", os.execute ~= nil)
+            claim("require", require ~= nil)
+            claim("sandbox", os.time ~= nil and load == nil and debug == nil)
+            claim("string", string.rep ~= nil)
+            string.rep = nil
+            if io then io.open = nil end"#;
+        load_programs(
+            &mut manager,
+            &[
+                (
+                    0,
+                    r#"claim("program 2 may use process")
+                    claim("program 4 may use network")"#,
+                ),
+                (1, check),
+                (2, check),
+                (3, check),
+                (4, r#"claim("os", (pcall(require, "os")))"#),
+                (5, r#"claim("program 1 may use process")"#),
+                (6, check),
+            ],
+        );
+        manager.grant_capability(3, Capability::Filesystem);
+        manager.grant_capability(6, Capability::Filesystem);
+        for program_id in [0, 5, 1, 2, 3, 4, 6] {
+            manager.run_program(program_id, static_db);
+        }
+
+        let db = static_db.lock().unwrap();
+        let facts = |program_id: i32| -> Vec<String> {
+            db.select(&vec![format!("#{} $name $allowed", program_id)])
+                .into_iter()
+                .map(|r| {
+                    format!(
+                        "{} {}",
+                        r.result[0].term.to_string(),
+                        r.result[1].term.to_string()
+                    )
+                })
+                .collect()
+        };
+        let expected = |filesystem: bool, process: bool| -> Vec<String> {
+            vec![
+                format!("io {}", filesystem || process),
+                format!("open {}", filesystem),
+                format!("popen {}", process),
+                format!("execute {}", process),
+                "require false".to_string(),
+                "sandbox true".to_string(),
+                "string true".to_string(),
+            ]
+        };
+        assert_eq!(facts(1), expected(false, false));
+        assert_eq!(facts(2), expected(false, true));
+        assert_eq!(facts(3), expected(true, false));
+        assert_eq!(facts(6), expected(true, false));
+        assert_eq!(facts(4), vec!["os false"]);
+        assert!(manager
+            .program_capabilities(4, &db)
+            .contains(&Capability::Network));
+    }
+}